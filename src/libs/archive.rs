@@ -0,0 +1,364 @@
+//! 保存每次验证码的尝试，方便之后分析
+//!
+//! 每次尝试一个文件夹，保存在状态文件夹的 `samples/` 里:
+//! - `sample.json`: 选项、海报信息、相似度、提交的答案及结果
+//! - `question.*`: 题图
+//! - `option{n}_{m}.*`: 每个选项的所有候选海报，按相关程度排列
+
+use crate::picparser::{Kaptcha, Ranking};
+use crate::poster::PosterMeta;
+use crate::site::Reward;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SAMPLE_FILE: &str = "sample.json";
+
+/// 选项的一张候选海报
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SampleCandidate {
+    pub poster: PosterMeta,
+    /// 海报文件名
+    pub file: String,
+    /// 下载时的 `Content-Type`
+    pub content_type: Option<String>,
+}
+
+/// 单个选项
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SampleOption {
+    pub name: String,
+    pub value: String,
+    /// 与题图最相似的候选海报
+    pub poster: Option<PosterMeta>,
+    /// 与题图最相似的候选海报的文件名
+    pub file: Option<String>,
+    pub score: Option<f64>,
+    pub error: Option<String>,
+    /// 所有候选海报，旧的样本中没有
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<SampleCandidate>,
+}
+
+/// 一次验证码尝试
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sample {
+    pub user: String,
+    pub time: u64,
+    pub matcher: String,
+    /// 题图文件名
+    pub question: String,
+    pub options: Vec<SampleOption>,
+    /// 提交的答案
+    pub submitted: Option<String>,
+    /// 网站返回的结果，未提交时是原因
+    pub verdict: String,
+    /// 正确答案，签到成功时自动填写，也可以手动标注
+    pub label: Option<String>,
+    /// 签到成功时获得的奖励
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reward: Option<Reward>,
+}
+
+impl Sample {
+    /// 根据题图及比较结果创建，此时还未提交
+    pub fn new(user: &str, matcher: &str, ranking: &Ranking) -> Self {
+        let scored = ranking
+            .scores()
+            .iter()
+            .map(|s| (&s.answer, Some(s.score), None));
+        let failed = ranking
+            .failed()
+            .iter()
+            .map(|f| (&f.answer, None, Some(f.error.to_string())));
+
+        let mut options: Vec<SampleOption> = scored
+            .chain(failed)
+            .map(|(a, score, error)| SampleOption {
+                name: a.name.clone(),
+                value: a.value.clone(),
+                poster: a.poster.clone(),
+                file: None,
+                score,
+                error,
+                candidates: vec![],
+            })
+            .collect();
+        options.sort_by(|a, b| a.value.cmp(&b.value));
+
+        Self {
+            user: user.into(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            matcher: matcher.into(),
+            question: String::new(),
+            options,
+            submitted: None,
+            verdict: String::new(),
+            label: None,
+            reward: None,
+        }
+    }
+
+    /// 记录提交的答案及结果
+    pub fn submit(&mut self, value: &str, success: bool, verdict: &str) {
+        self.submitted = Some(value.into());
+        self.verdict = verdict.into();
+        if success {
+            self.label = Some(value.into());
+        }
+    }
+
+    /// 记录未提交的原因
+    pub fn skip(&mut self, reason: &str) {
+        self.verdict = reason.into();
+    }
+}
+
+impl Display for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Sample[{} {} options: {} submitted: {} label: {} {}]",
+            self.time,
+            self.user,
+            self.options.len(),
+            self.submitted.as_deref().unwrap_or("-"),
+            self.label.as_deref().unwrap_or("-"),
+            self.verdict
+        )
+    }
+}
+
+/// 样本存档
+#[derive(Debug)]
+pub struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    /// 在 `state_dir` 下创建
+    pub fn new<P>(state_dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            dir: state_dir.as_ref().join("samples"),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 保存一次尝试
+    ///
+    /// 同时保存题图及每个选项的所有候选海报，之后评估时可以重新比较
+    pub fn save(
+        &self,
+        mut sample: Sample,
+        kaptcha: &Kaptcha,
+        ranking: &Ranking,
+    ) -> Result<PathBuf> {
+        // 没有题图时不创建文件夹，避免留下空的样本
+        let Some(ref question) = kaptcha.raw_bytes else {
+            return Err(anyhow!("没有题图"));
+        };
+
+        let name = format!("{}_{}", sample.time, sanitize(&sample.user));
+        let mut dir = self.dir.join(&name);
+        // 同一秒内的多次尝试
+        let mut n = 1;
+        while dir.exists() {
+            dir = self.dir.join(format!("{}_{}", name, n));
+            n += 1;
+        }
+        fs::create_dir_all(&dir)?;
+
+        sample.question = write_image(&dir, "question", question)?;
+
+        for (i, option) in sample.options.iter_mut().enumerate() {
+            let Some(answer) = ranking.answers().find(|a| a.value == option.value) else {
+                continue;
+            };
+            for (j, c) in answer.candidates.iter().enumerate() {
+                let file = write_image(&dir, &format!("option{}_{}", i, j), &c.raw_bytes)?;
+                let chosen = answer.poster.as_ref().map(|p| &p.url) == Some(&c.poster.url);
+                if chosen && option.file.is_none() {
+                    option.file = Some(file.clone());
+                }
+                option.candidates.push(SampleCandidate {
+                    poster: c.poster.clone(),
+                    file,
+                    content_type: c.content_type.clone(),
+                });
+            }
+            // 没有候选海报信息时只保存选定的海报
+            if let (None, Some(raw)) = (&option.file, &answer.raw_bytes) {
+                option.file = Some(write_image(&dir, &format!("option{}", i), raw)?);
+            }
+        }
+
+        fs::write(dir.join(SAMPLE_FILE), serde_json::to_vec_pretty(&sample)?)?;
+        Ok(dir)
+    }
+
+    /// 所有样本，按时间从旧到新排列
+    pub fn list(&self) -> Result<Vec<(PathBuf, Sample)>> {
+        list_samples(&self.dir)
+    }
+
+    /// 删除 `days` 天以前的样本
+    ///
+    /// 返回删除的数量
+    pub fn prune(&self, days: u64) -> Result<usize> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let expired = now.saturating_sub(days * 24 * 60 * 60);
+
+        let mut removed = 0;
+        for (path, sample) in self.list()? {
+            if sample.time < expired {
+                fs::remove_dir_all(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// 读取文件夹中的所有样本，按时间从旧到新排列
+///
+/// 无法解析的会被跳过
+pub fn list_samples(dir: &Path) -> Result<Vec<(PathBuf, Sample)>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut samples = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Ok(file) = File::open(path.join(SAMPLE_FILE)).map(BufReader::new) else {
+            continue;
+        };
+        match serde_json::from_reader::<_, Sample>(file) {
+            Ok(sample) => samples.push((path, sample)),
+            Err(e) => log::debug!("无法解析样本: {}, Err: {}", path.display(), e),
+        }
+    }
+    samples.sort_by_key(|(_, s)| s.time);
+    Ok(samples)
+}
+
+/// 写入图片，扩展名根据内容判断
+fn write_image(dir: &Path, name: &str, b: &Bytes) -> Result<String> {
+    let ext = image::guess_format(b)
+        .ok()
+        .and_then(|f| f.extensions_str().first().copied())
+        .unwrap_or("bin");
+    let file = format!("{}.{}", name, ext);
+    fs::write(dir.join(&file), b)?;
+    Ok(file)
+}
+
+/// 用户名可能包含不能作为文件名的字符
+pub(crate) fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod archive_test {
+    use super::*;
+    use crate::picparser::{Answer, Scored};
+
+    fn temp_archive(name: &str) -> Archive {
+        let dir =
+            std::env::temp_dir().join(format!("tjuptatt_archive_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Archive::new(dir)
+    }
+
+    fn ranking() -> Ranking {
+        let scored = |name: &str, score: f64, raw: &'static [u8]| {
+            let mut answer = Answer::new(name.into(), name.into());
+            answer.raw_bytes = Some(Bytes::from_static(raw));
+            Scored { answer, score }
+        };
+        Ranking::new(vec![scored("a", 99.0, b"aaa"), scored("b", 60.0, b"bbb")])
+    }
+
+    fn kaptcha() -> Kaptcha {
+        let mut kaptcha = Kaptcha::new("https://example.com/q.jpg".into());
+        kaptcha.raw_bytes = Some(Bytes::from_static(b"question"));
+        kaptcha
+    }
+
+    #[test]
+    fn save_test() {
+        let archive = temp_archive("save");
+        let ranking = ranking();
+
+        // 没有题图时不留下文件夹
+        let sample = Sample::new("alice", "dssim", &ranking);
+        let empty = Kaptcha::new("https://example.com/q.jpg".into());
+        assert!(archive.save(sample, &empty, &ranking).is_err());
+        assert!(!archive.dir().exists());
+
+        let mut sample = Sample::new("alice@tju", "dssim", &ranking);
+        sample.submit("a", true, "签到成功");
+        let first = archive.save(sample.clone(), &kaptcha(), &ranking).unwrap();
+        // 同一秒内的第二次尝试
+        let second = archive.save(sample, &kaptcha(), &ranking).unwrap();
+        assert_ne!(first, second);
+        assert!(first.join("question.bin").is_file());
+        assert!(first.join("option0.bin").is_file());
+        assert!(first.join("option1.bin").is_file());
+
+        let samples = archive.list().unwrap();
+        assert_eq!(samples.len(), 2);
+        let (_, saved) = &samples[0];
+        assert_eq!(saved.user, "alice@tju");
+        assert_eq!(saved.question, "question.bin");
+        assert_eq!(saved.label.as_deref(), Some("a"));
+        let files: Vec<_> = saved.options.iter().map(|o| o.file.as_deref()).collect();
+        assert_eq!(files, vec![Some("option0.bin"), Some("option1.bin")]);
+
+        fs::remove_dir_all(archive.dir()).unwrap();
+    }
+
+    #[test]
+    fn prune_test() {
+        let archive = temp_archive("prune");
+        let ranking = ranking();
+
+        let mut old = Sample::new("alice", "dssim", &ranking);
+        old.time -= 10 * 24 * 60 * 60;
+        old.skip("答案不确定");
+        archive.save(old, &kaptcha(), &ranking).unwrap();
+        let new = Sample::new("alice", "dssim", &ranking);
+        archive.save(new, &kaptcha(), &ranking).unwrap();
+        // 无法解析的文件夹会被跳过
+        fs::create_dir_all(archive.dir().join("other")).unwrap();
+
+        assert_eq!(archive.list().unwrap().len(), 2);
+        assert_eq!(archive.prune(7).unwrap(), 1);
+        let samples = archive.list().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert!(samples[0].1.verdict.is_empty());
+        assert_eq!(archive.prune(7).unwrap(), 0);
+
+        fs::remove_dir_all(archive.dir()).unwrap();
+    }
+}
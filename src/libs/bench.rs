@@ -0,0 +1,491 @@
+//! 离线评估验证码的识别效果
+//!
+//! 使用 `samples` 保存的样本，不访问网络，
+//! 对每种相似度计算方式统计准确率、误判率、不明确率及各阶段耗时
+//!
+//! 与签到时一样，每个选项比较所有候选海报(最多 `candidates` 张)，取相似度最高的
+
+use crate::{
+    archive::{list_samples, Sample},
+    config::CaptchaConfig,
+    matcher::{self, Matcher},
+    picparser::{reseize_pic, Answer, Ranking, Resize, Scored},
+};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::{
+    fmt::Display,
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// 单个样本的识别结果
+enum Outcome {
+    Correct,
+    Wrong,
+    Ambiguous,
+}
+
+/// 一种相似度计算方式的评估结果
+#[derive(Default)]
+pub struct BenchReport {
+    pub matcher: String,
+    pub correct: usize,
+    /// 提交了错误的答案
+    pub wrong: usize,
+    /// 不会提交
+    pub ambiguous: usize,
+    /// 没有标注或者无法读取的样本
+    pub skipped: usize,
+    /// 解码及缩放图片
+    pub resize: Duration,
+    /// 预处理图片
+    pub prepare: Duration,
+    /// 计算相似度
+    pub compare: Duration,
+}
+
+impl BenchReport {
+    /// 参与评估的样本数量
+    pub fn total(&self) -> usize {
+        self.correct + self.wrong + self.ambiguous
+    }
+
+    fn rate(&self, n: usize) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => n as f64 / total as f64 * 100.0,
+        }
+    }
+
+    /// 准确率
+    pub fn accuracy(&self) -> f64 {
+        self.rate(self.correct)
+    }
+
+    /// 误判率
+    pub fn false_positive(&self) -> f64 {
+        self.rate(self.wrong)
+    }
+
+    /// 不明确率
+    pub fn ambiguity(&self) -> f64 {
+        self.rate(self.ambiguous)
+    }
+
+    fn avg(&self, d: Duration) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => d.as_secs_f64() * 1000.0 / total as f64,
+        }
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<32} 样本: {:>4} 准确: {:>6.2}% 误判: {:>6.2}% 不明确: {:>6.2}% \
+            跳过: {:>3} | 平均耗时(ms) 缩放: {:>7.2} 预处理: {:>7.2} 比较: {:>7.2}",
+            self.matcher,
+            self.total(),
+            self.accuracy(),
+            self.false_positive(),
+            self.ambiguity(),
+            self.skipped,
+            self.avg(self.resize),
+            self.avg(self.prepare),
+            self.avg(self.compare)
+        )
+    }
+}
+
+/// 选出答案及缩放图片的参数
+struct Params {
+    limit: f64,
+    margin: f64,
+    resize: Resize,
+    /// 每个选项最多比较几张候选海报
+    candidates: usize,
+}
+
+/// 读取到内存里的候选海报及其 `Content-Type`
+type Poster = (Bytes, Option<String>);
+
+/// 读取到内存里的样本
+struct Loaded {
+    label: String,
+    question: Bytes,
+    options: Vec<(Answer, Vec<Poster>)>,
+}
+
+/// 使用验证码设置对 `dir` 中有标注的样本进行评估
+pub fn run<P>(dir: P, config: &CaptchaConfig) -> Result<BenchReport>
+where
+    P: AsRef<Path>,
+{
+    let matcher: Arc<dyn Matcher> = matcher::from_config(config);
+    let params = Params {
+        limit: config.threshold(),
+        margin: config.margin(),
+        resize: config.into(),
+        candidates: config.candidates(),
+    };
+    let mut report = BenchReport {
+        matcher: matcher.name(),
+        ..Default::default()
+    };
+
+    for (path, sample) in list_samples(dir.as_ref())? {
+        let loaded = match load(&path, sample, params.candidates) {
+            Ok(l) => l,
+            Err(e) => {
+                log::debug!("跳过样本: {}, {}", path.display(), e);
+                report.skipped += 1;
+                continue;
+            }
+        };
+        match bench_one(&loaded, matcher.as_ref(), &params, &mut report) {
+            Ok(Outcome::Correct) => report.correct += 1,
+            Ok(Outcome::Wrong) => {
+                log::debug!("误判: {}", path.display());
+                report.wrong += 1
+            }
+            Ok(Outcome::Ambiguous) => report.ambiguous += 1,
+            Err(e) => {
+                log::debug!("跳过样本: {}, {}", path.display(), e);
+                report.skipped += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// 读取样本的图片，每个选项最多 `n` 张候选海报
+///
+/// 旧的样本中没有候选海报，只有选定的一张
+fn load(path: &Path, sample: Sample, n: usize) -> Result<Loaded> {
+    let Some(label) = sample.label else {
+        return Err(anyhow!("没有标注"));
+    };
+    let read = |file: &str| -> Result<Bytes> { Ok(fs::read(path.join(file))?.into()) };
+
+    let question = read(&sample.question)?;
+    let options = sample
+        .options
+        .into_iter()
+        .filter_map(|o| {
+            let posters: Vec<Poster> = if o.candidates.is_empty() {
+                vec![(read(o.file.as_deref()?).ok()?, None)]
+            } else {
+                o.candidates
+                    .iter()
+                    .take(n)
+                    .filter_map(|c| Some((read(&c.file).ok()?, c.content_type.clone())))
+                    .collect()
+            };
+            Some((Answer::new(o.name, o.value), posters))
+        })
+        .collect();
+
+    Ok(Loaded {
+        label,
+        question,
+        options,
+    })
+}
+
+fn bench_one(
+    loaded: &Loaded,
+    matcher: &dyn Matcher,
+    params: &Params,
+    report: &mut BenchReport,
+) -> Result<Outcome> {
+    let start = Instant::now();
+    let question = reseize_pic(loaded.question.clone(), None, params.resize)?;
+    report.resize += start.elapsed();
+
+    let start = Instant::now();
+    let orig = matcher.prepare(&question)?;
+    report.prepare += start.elapsed();
+
+    let mut scores = vec![];
+    for (answer, posters) in loaded.options.iter() {
+        // 与签到时一样取相似度最高的候选海报，相同时取靠前的
+        let mut best: Option<f64> = None;
+        for (raw, content_type) in posters.iter() {
+            let start = Instant::now();
+            let Ok(pic) = reseize_pic(raw.clone(), content_type.as_deref(), params.resize) else {
+                continue;
+            };
+            report.resize += start.elapsed();
+
+            let start = Instant::now();
+            let Ok(modif) = matcher.prepare(&pic) else {
+                continue;
+            };
+            report.prepare += start.elapsed();
+
+            let start = Instant::now();
+            let Ok(score) = matcher.compare(&orig, &modif) else {
+                continue;
+            };
+            report.compare += start.elapsed();
+
+            if best.is_none_or(|b| score > b) {
+                best = Some(score);
+            }
+        }
+
+        if let Some(score) = best {
+            scores.push(Scored {
+                answer: answer.clone(),
+                score,
+            });
+        }
+    }
+
+    let ranking = Ranking::new(scores);
+    Ok(match ranking.pick(params.limit, params.margin) {
+        Ok(s) if s.answer.value == loaded.label => Outcome::Correct,
+        Ok(_) => Outcome::Wrong,
+        Err(_) => Outcome::Ambiguous,
+    })
+}
+
+#[cfg(test)]
+mod bench_test {
+    use super::*;
+    use crate::{
+        archive::Archive,
+        config::MatcherKind,
+        picparser::{Candidate, Kaptcha, Ranking},
+        poster::PosterMeta,
+    };
+    use image::{
+        codecs::jpeg::JpegEncoder,
+        imageops::{self, FilterType},
+        ImageFormat, Rgb, RgbImage,
+    };
+    use std::io::Cursor;
+
+    /// 生成 JPEG 图片，`vertical` 决定渐变的方向
+    fn jpeg(vertical: bool) -> Bytes {
+        let img = RgbImage::from_fn(60, 100, |x, y| {
+            let v = if vertical { y * 2 } else { x * 4 };
+            Rgb([v as u8, 255 - v as u8, 128])
+        });
+        let mut b = Vec::new();
+        img.write_to(&mut Cursor::new(&mut b), ImageFormat::Jpeg)
+            .unwrap();
+        b.into()
+    }
+
+    /// 保存一个样本，题图是横向渐变，`options` 是选项的值及海报
+    fn save(archive: &Archive, options: &[(&str, Bytes)], label: Option<&str>) {
+        let scores = options
+            .iter()
+            .map(|(value, raw)| {
+                let mut answer = Answer::new(value.to_string(), value.to_string());
+                answer.raw_bytes = Some(raw.clone());
+                Scored { answer, score: 0.0 }
+            })
+            .collect();
+        save_ranking(archive, Ranking::new(scores), label);
+    }
+
+    fn save_ranking(archive: &Archive, ranking: Ranking, label: Option<&str>) {
+        let mut kaptcha = Kaptcha::new(String::new());
+        kaptcha.raw_bytes = Some(jpeg(false));
+
+        let mut sample = Sample::new("alice", "dssim", &ranking);
+        sample.label = label.map(String::from);
+        archive.save(sample, &kaptcha, &ranking).unwrap();
+    }
+
+    #[test]
+    fn run_test() {
+        let dir = std::env::temp_dir().join(format!("tjuptatt_bench_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let archive = Archive::new(&dir);
+
+        let (same, other) = (jpeg(false), jpeg(true));
+        save(
+            &archive,
+            &[("a", same.clone()), ("b", other.clone())],
+            Some("a"),
+        );
+        save(
+            &archive,
+            &[("a", same.clone()), ("b", other.clone())],
+            Some("b"),
+        );
+        save(
+            &archive,
+            &[("a", same.clone()), ("b", same.clone())],
+            Some("a"),
+        );
+        save(&archive, &[("a", same), ("b", other)], None);
+
+        let report = run(archive.dir(), &CaptchaConfig::default()).unwrap();
+        assert_eq!(
+            (
+                report.correct,
+                report.wrong,
+                report.ambiguous,
+                report.skipped
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(report.total(), 3);
+        assert!((report.accuracy() - 100.0 / 3.0).abs() < 1e-9);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 有候选海报的选项
+    fn answer(value: &str, posters: &[&Bytes], chosen: usize) -> Answer {
+        let mut answer = Answer::new(value.into(), value.into());
+        for (i, raw) in posters.iter().enumerate() {
+            let poster = PosterMeta {
+                title: value.into(),
+                sub_title: None,
+                year: None,
+                url: format!("{}-{}", value, i),
+            };
+            if i == chosen {
+                answer.poster = Some(poster.clone());
+                answer.raw_bytes = Some((*raw).clone());
+            }
+            answer.candidates.push(Candidate {
+                poster,
+                raw_bytes: (*raw).clone(),
+                content_type: Some("image/jpeg".into()),
+                img_bytes: Bytes::new(),
+            });
+        }
+        answer
+    }
+
+    #[test]
+    fn candidates_test() {
+        let dir = std::env::temp_dir().join(format!("tjuptatt_bench_c_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let archive = Archive::new(&dir);
+
+        // 选项 a 的第二张候选海报才与题图相同
+        let (same, other) = (jpeg(false), jpeg(true));
+        let scores = vec![
+            Scored {
+                answer: answer("a", &[&other, &same], 1),
+                score: 0.0,
+            },
+            Scored {
+                answer: answer("b", &[&other], 0),
+                score: 0.0,
+            },
+        ];
+        save_ranking(&archive, Ranking::new(scores), Some("a"));
+
+        let samples = archive.list().unwrap();
+        let option = &samples[0].1.options[0];
+        assert_eq!(option.candidates.len(), 2);
+        assert_eq!(option.file.as_deref(), Some("option0_1.jpg"));
+        assert_eq!(option.candidates[1].poster.url, "a-1");
+
+        let report = run(archive.dir(), &CaptchaConfig::default()).unwrap();
+        assert_eq!(report.correct, 1);
+        // 只比较第一张候选海报时无法区分
+        let config: CaptchaConfig = toml::from_str("candidates = 1").unwrap();
+        let report = run(archive.dir(), &config).unwrap();
+        assert_eq!(report.ambiguous, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// 由几块颜色不同的光斑组成的海报，`seed` 不同的图片互不相关
+    fn poster(seed: u32) -> RgbImage {
+        let blobs: Vec<_> = (0..6u32)
+            .map(|i| {
+                let h = (seed * 31 + i * 17).wrapping_mul(2654435761);
+                let (x, y) = ((h % 120) as f64, (h / 120 % 200) as f64);
+                let color = [h >> 8, h >> 16, h >> 24].map(|c| (c % 256) as f64);
+                (x, y, color)
+            })
+            .collect();
+        RgbImage::from_fn(120, 200, |x, y| {
+            let mut px = [32.0; 3];
+            for (bx, by, color) in blobs.iter() {
+                let d2 = (x as f64 - bx).powi(2) + (y as f64 - by).powi(2);
+                let w = (-d2 / 800.0).exp();
+                for c in 0..3 {
+                    px[c] += w * color[c];
+                }
+            }
+            Rgb(px.map(|v| v.min(255.0) as u8))
+        })
+    }
+
+    fn encode(img: &RgbImage, quality: u8) -> Bytes {
+        let mut b = Vec::new();
+        JpegEncoder::new_with_quality(&mut b, quality)
+            .encode_image(img)
+            .unwrap();
+        b.into()
+    }
+
+    /// 保存一个样本，题图是缩放并压缩过的 `orig`
+    fn save_posters(dir: &Path, orig: &RgbImage, posters: Vec<RgbImage>) -> Archive {
+        let _ = fs::remove_dir_all(dir);
+        let archive = Archive::new(dir);
+        let question = imageops::resize(orig, 90, 150, FilterType::Triangle);
+        let scores = posters
+            .iter()
+            .enumerate()
+            .map(|(i, img)| {
+                let value = ((b'a' + i as u8) as char).to_string();
+                let mut answer = Answer::new(value.clone(), value);
+                answer.raw_bytes = Some(encode(img, 90));
+                Scored { answer, score: 0.0 }
+            })
+            .collect();
+        let mut kaptcha = Kaptcha::new(String::new());
+        kaptcha.raw_bytes = Some(encode(&question, 50));
+        let ranking = Ranking::new(scores);
+        let mut sample = Sample::new("alice", "dssim", &ranking);
+        sample.label = Some("a".into());
+        archive.save(sample, &kaptcha, &ranking).unwrap();
+        archive
+    }
+
+    /// 各种计算方式使用自己的默认阈值时都能认出缩放及压缩过的题图，
+    /// 没有正确的海报时不会选择不相关的图片
+    #[test]
+    fn default_threshold_test() {
+        let dir = std::env::temp_dir().join(format!("tjuptatt_bench_t_{}", std::process::id()));
+        let orig = poster(1);
+        let found = save_posters(
+            &dir.join("found"),
+            &orig,
+            vec![orig.clone(), poster(2), poster(3), poster(4)],
+        );
+        let missing = save_posters(
+            &dir.join("missing"),
+            &orig,
+            vec![poster(2), poster(3), poster(4)],
+        );
+
+        for kind in MatcherKind::ALL {
+            let config = CaptchaConfig::overrides(Some(kind), None, None, None);
+            let report = run(found.dir(), &config).unwrap();
+            assert_eq!(report.correct, 1, "{}", config);
+            let report = run(missing.dir(), &config).unwrap();
+            assert_eq!(report.ambiguous, 1, "{}", config);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
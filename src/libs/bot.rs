@@ -2,82 +2,196 @@
 
 use crate::config::EmailConfig;
 use crate::{
-    command::{tjurls, DIRS},
-    config::{ConfigFile, UserConfig},
+    archive::{Archive, Sample},
+    bench,
+    cache::PosterCache,
+    cookies, email_bot,
+    http::Session,
+    login::{LoginError, LoginFailure},
+    matcher,
+    outcome::{AttendanceOutcome, Chosen, Failure},
+    picparser,
+    poster::PosterChain,
+    site::{Challenge, MakeUp, PageState, Profile, Site, Sites},
+};
+use crate::{
+    command::DIRS,
+    config::{
+        BackoffConfig, CaptchaConfig, ConfigFile, HttpConfig, MakeUpConfig, MatcherKind,
+        ProxyConfig, RateLimitConfig, UserConfig,
+    },
 };
-use crate::{email_bot, picparser};
 use anyhow::{anyhow, Result};
-use lazy_static::lazy_static;
-use reqwest::{header::HeaderMap, redirect, Client, ClientBuilder};
+use clap::ArgMatches;
+use cookie_store::CookieExpiration;
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
-use scraper::{Html, Selector};
+use serde::Serialize;
 use std::{
+    fmt::Display,
     fs::{remove_dir_all, File},
     io::{BufReader, Write},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
 };
 
-lazy_static! {
-    static ref HEADER: HeaderMap = {
-        let mut head = HeaderMap::new();
-        head.insert(
-            "User-Agent",
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) \
-            AppleWebKit/537.36 (KHTML, like Gecko) \
-            Chrome/100.0.0.0 Safari/537.36"
-                .parse()
-                .unwrap(),
-        );
-        head
-    };
+/// 一次签到最多连续补签的次数
+const MAX_MAKEUPS: usize = 31;
+
+/// 登陆状态
+pub struct SessionStatus {
+    pub user: String,
+    pub site: String,
+    /// 是否有cookie文件
+    pub cookie: bool,
+    /// 是否已登陆，`false` 表示被重定向到了登陆页面
+    pub logged_in: bool,
+    /// 今天是否已经签到，`None` 表示无法判断
+    pub attended: Option<bool>,
+    /// 站点cookie中最早的过期时间(UTC)
+    pub expires: Option<String>,
+}
+
+impl Display for SessionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let login = match (self.cookie, self.logged_in) {
+            (false, _) => "没有cookie文件",
+            (true, true) => "已登陆",
+            (true, false) => "未登陆(重定向到登陆页面)",
+        };
+        let attended = match self.attended {
+            Some(true) => "今天已签到",
+            Some(false) => "今天未签到",
+            None => "签到状态未知",
+        };
+        write!(
+            f,
+            "{}@{}: {} | {} | cookie过期时间: {}",
+            self.user,
+            self.site,
+            login,
+            attended,
+            self.expires.as_deref().unwrap_or("-")
+        )
+    }
+}
 
-    // //input[@type="radio"]
-    // //input[@type="submit"]
-    static ref INPUT_RADIO_SELE: Selector = Selector::parse(r#"input[type="radio"]"#).unwrap();
-    // static ref INPUT_SUBMIT_SELE: Selector = Selector::parse(r#"input[type="submit"]"#).unwrap();
+/// 账号信息，查询失败时记录错误
+#[derive(Serialize)]
+pub struct AccountInfo {
+    pub user: String,
+    pub site: String,
+    #[serde(flatten)]
+    pub profile: Profile,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AccountInfo {
+    const HEADER: [&'static str; 10] = [
+        "用户", "站点", "用户名", "上传量", "下载量", "分享率", "魔力值", "等级", "邀请", "未读",
+    ];
 
-    static ref TD: Selector = Selector::parse(r#"td[id="outer"]"#).unwrap();
-    static ref IMG: Selector = Selector::parse("img").unwrap();
+    /// 表格中的一行
+    fn row(&self) -> Vec<String> {
+        fn or_dash<T: Display>(v: &Option<T>) -> String {
+            v.as_ref().map_or("-".into(), |v| v.to_string())
+        }
+        let p = &self.profile;
+        let mut row = vec![self.user.clone(), self.site.clone()];
+        if let Some(ref e) = self.error {
+            row.push(format!("查询失败: {}", e));
+            return row;
+        }
+        row.extend([
+            or_dash(&p.name),
+            or_dash(&p.uploaded),
+            or_dash(&p.downloaded),
+            p.ratio.map_or("-".into(), |r| format!("{:.3}", r)),
+            or_dash(&p.bonus),
+            or_dash(&p.class),
+            or_dash(&p.invites),
+            or_dash(&p.unread),
+        ]);
+        row
+    }
 
+    /// 以表格的形式打印
+    fn print_table(infos: &[Self]) {
+        // 中文字符占两个宽度
+        let width = |s: &str| -> usize {
+            s.chars()
+                .map(|c| if c > '\u{2e80}' { 2 } else { 1 })
+                .sum()
+        };
+        let mut rows = vec![Self::HEADER.iter().map(|h| h.to_string()).collect::<Vec<_>>()];
+        rows.extend(infos.iter().map(Self::row));
+
+        let mut widths = vec![0; Self::HEADER.len()];
+        for row in rows.iter() {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(width(cell));
+            }
+        }
+        for row in rows.iter() {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, w)| format!("{}{}", cell, " ".repeat(w.saturating_sub(width(cell)))))
+                .collect::<Vec<_>>()
+                .join("  ");
+            println!("{}", line.trim_end());
+        }
+    }
 }
 
 /// tjupt user
 #[derive(Debug)]
 pub struct TjuPtUser {
     config: UserConfig,
-    client: Client,
+    client: Session,
     cookie: Arc<CookieStoreMutex>,
     cookie_path: Option<PathBuf>,
+    posters: Arc<PosterChain>,
+    archive: Option<Arc<Archive>>,
+    site: Arc<dyn Site>,
+    makeup: MakeUpConfig,
+    /// 本次运行补签已经花费的魔力值
+    makeup_spent: Mutex<f64>,
 }
 
 impl TjuPtUser {
-    pub fn from_config<P>(userconfig: UserConfig, status_dir: Option<P>) -> Self
+    pub fn from_config<P>(
+        userconfig: UserConfig,
+        status_dir: Option<P>,
+        posters: Arc<PosterChain>,
+        site: Arc<dyn Site>,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let cookie_path =
-            status_dir.map(|p| p.as_ref().join(format!("{}_cookie.json", userconfig.id())));
+        // 默认站点的cookie文件名保持不变
+        let cookie_file = match userconfig.site() {
+            None => format!("{}_cookie.json", userconfig.id()),
+            Some(site) => format!("{}_{}_cookie.json", site, userconfig.id()),
+        };
+        let cookie_path = status_dir.map(|p| p.as_ref().join(cookie_file));
 
-        Self::new(userconfig, cookie_path)
+        Self::new(userconfig, cookie_path, posters, site)
     }
 
-    fn new<P>(config: UserConfig, cookie_path: Option<P>) -> Self
+    fn new<P>(
+        config: UserConfig,
+        cookie_path: Option<P>,
+        posters: Arc<PosterChain>,
+        site: Arc<dyn Site>,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
         let cookie = Arc::new(CookieStoreMutex::default());
 
-        let client = ClientBuilder::new()
-            .timeout(Duration::from_secs(5))
-            .cookie_store(true)
-            .cookie_provider(cookie.clone())
-            .connection_verbose(false)
-            .default_headers(HEADER.clone())
-            .redirect(redirect::Policy::limited(3))
-            .build()
-            .unwrap();
+        let client = Session::new(&config.http(), &config.proxy(), cookie.clone())
+            .map_err(|e| anyhow!("{} 的网络设置有误: {}", config.id(), e))?;
 
         let cookie_path = match cookie_path {
             None => None,
@@ -87,12 +201,29 @@ impl TjuPtUser {
             }
         };
 
-        Self {
+        Ok(Self {
             client,
             config,
             cookie,
             cookie_path,
-        }
+            posters,
+            archive: None,
+            site,
+            makeup: MakeUpConfig::default(),
+            makeup_spent: Mutex::new(0.0),
+        })
+    }
+
+    /// 保存每次验证码的尝试
+    pub fn with_archive(mut self, archive: Arc<Archive>) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// 补签设置
+    pub fn with_makeup(mut self, makeup: MakeUpConfig) -> Self {
+        self.makeup = makeup;
+        self
     }
 
     /// 加载cookie
@@ -116,130 +247,173 @@ impl TjuPtUser {
         Ok(())
     }
 
-    /// 在不加载cookie的情况下登陆
-    ///
-    /// 返回签到页面的String
-    async fn login(&self) -> Result<String> {
-        let _r = self.client.get(tjurls::LOGIN).send().await?;
-        if !self
-            .client
-            .post(tjurls::TAKELOGIN)
-            // .query(&[("returnto", "attendance.php")])
-            .form(&[
-                ("username", self.config.id()),
-                ("password", self.config.pwd()),
-                ("logout", "7days"),
-                ("returnto", "attendance.php"),
-            ])
-            .send()
-            .await?
-            .status()
-            .is_success()
-        {
-            return Err(anyhow!("请检查网络"));
-        }
-        let req = self.client.get(tjurls::ATTENDANCE).send().await?;
-        if !req.url().as_str().contains("login.php") {
-            let content = req.text().await?;
-            Ok(content)
-        } else {
-            Err(anyhow!("发送登陆请求失败"))
-        }
-    }
-
-    /// 登陆
-    /// 在这之前加载过cookie了
-    async fn get_att_html(&self) -> Result<String> {
-        let req = self.client.get(tjurls::ATTENDANCE).send().await?;
-        // 先获取签到页面，检查链接
-        if req.url().as_str().contains("login.php") {
-            // 如果重定向了说明需要登陆
-            self.login().await
-        } else {
-            // 如果成功，那么就直接ok
-            let content = req.text().await?;
-            Ok(content)
-        }
-    }
-
     /// 签到一次
     ///
     /// 但是不在这里加载cookie
     /// 也不在这登录
-    async fn att_onece_now(&self) -> Result<()> {
-        let Ok(html) = self.get_att_html().await else {
-            return Err(anyhow!("{} 登录失败", self.config.id()));
+    async fn att_onece_now(&self) -> AttendanceOutcome {
+        let challenge = match self.challenge().await {
+            Ok(challenge) => challenge,
+            Err(outcome) => return outcome,
         };
 
-        // 解析网页，获取选项信息
-        let doc = Html::parse_document(&html);
-
-        // //input[@type="radio"]s
-        let radio = doc.select(&INPUT_RADIO_SELE);
-
-        // 选项们
-        let answers = radio
+        // 获取结果
+        let answers: Vec<_> = challenge
+            .answers
             .into_iter()
-            .filter_map(|e| {
-                let Some(name) = e.next_sibling()  else {
-                return None;
-            };
-                let Some(name) = name.value().as_text() else {
-                return None;
-            };
-                // let name = name.to_string();
+            .map(picparser::Answer::from)
+            .collect();
+        let mut kaptcha = picparser::Kaptcha::new(challenge.img_url);
+        let captcha = self.config.captcha();
+        let matcher = matcher::from_config(&captcha);
 
-                let Some(value) = e.value().attr("value") else {
-                return None;
-            };
+        let ranking = match kaptcha
+            .compare_with_answers(
+                &answers,
+                &self.client,
+                &self.posters,
+                matcher.clone(),
+                (&captcha).into(),
+                captcha.candidates(),
+            )
+            .await
+        {
+            Ok(ranking) => ranking,
+            Err(e) => return AttendanceOutcome::failed(Failure::Other, e, None),
+        };
 
-                Some((name.to_string(), value.to_string()))
-            })
-            .collect::<Vec<_>>();
-
-        // 图片
-        let Some(img) = doc.select(&TD).next().
-            and_then(|e|e.select(&IMG).next())
-            .and_then(|e| e.value().attr("src") ) 
-        else {
-            return Err(anyhow!("无法定位图片"));
+        log::debug!(
+            "{} 比较结果({}): {}",
+            self.config.id(),
+            matcher.name(),
+            ranking
+        );
+        let mut sample = Sample::new(self.config.id(), &matcher.name(), &ranking);
+
+        let outcome = match ranking.pick(captcha.threshold(), captcha.margin()) {
+            Ok(result) => {
+                log::info!("结果是: {}", result);
+                let chosen = Chosen::from(result);
+                let res = self.site.submit(&self.client, &result.answer.value).await;
+                let verdict = match res {
+                    Ok(ref reward) if reward.is_empty() => "签到成功".to_string(),
+                    Ok(ref reward) => format!("签到成功 {}", reward),
+                    Err(ref e) => e.to_string(),
+                };
+                sample.submit(&result.answer.value, res.is_ok(), &verdict);
+                if let Ok(ref reward) = res {
+                    sample.reward = Some(reward.clone());
+                }
+                match res {
+                    Ok(reward) => AttendanceOutcome::Success {
+                        attempts: 1,
+                        answer: Some(chosen),
+                        reward,
+                    },
+                    Err(e) => AttendanceOutcome::failed(Failure::Rejected, e, Some(chosen)),
+                }
+            }
+            Err(e) => {
+                sample.skip(&e.to_string());
+                match ranking.best() {
+                    // 所有选项都没有海报
+                    None => AttendanceOutcome::failed(Failure::Poster, e, None),
+                    Some(best) => {
+                        AttendanceOutcome::failed(Failure::Ambiguous, e, Some(best.into()))
+                    }
+                }
+            }
         };
 
-        let img_url = format!("https://tjupt.org{}", img);
+        if let Some(ref archive) = self.archive {
+            match archive.save(sample, &kaptcha, &ranking) {
+                Ok(p) => log::debug!("保存样本: {}", p.display()),
+                Err(e) => log::warn!("无法保存样本: {}", e),
+            }
+        }
 
-        // log::debug!("获取的图片链接: {}", img_url);
-        // 这里检查一下图片应该是jpg结尾的
-        // if !img_url.ends_with(".jpg") {
-        //     return Err(anyhow!("无法获取jpg格式图片"));
-        // }
+        outcome
+    }
 
-        // for (x, y) in answers.iter() {
-        //     log::debug!("选项: {}, {}", x, y);
-        // }
+    /// 获取今天的题目，漏签时按照设置先补签
+    ///
+    /// 没有题目时返回本次签到的结果，比如今天已经签到过了
+    async fn challenge(&self) -> std::result::Result<Challenge, AttendanceOutcome> {
+        for _ in 0..=MAX_MAKEUPS {
+            let html = match self.site.attendance_page(&self.client, &self.config).await {
+                Ok(html) => html,
+                Err(e) => {
+                    let failure = match e.downcast_ref::<LoginError>() {
+                        Some(login) => login.failure,
+                        None if e.is::<reqwest::Error>() => LoginFailure::Transport,
+                        None => LoginFailure::Unknown,
+                    };
+                    return Err(AttendanceOutcome::failed(Failure::Login(failure), e, None));
+                }
+            };
 
-        if answers.is_empty() {
-            // 如果是空的，说明签到完了，或者需要补签
-            return Err(anyhow!("无法找到选项，可能已经签到，或需要补签"));
+            // 解析网页，获取选项信息
+            // `Html` 不能跨越 await，所以在同步函数中解析
+            let state = self
+                .site
+                .page_state(&html)
+                .map_err(|e| AttendanceOutcome::failed(Failure::Other, e, None))?;
+            match state {
+                PageState::Challenge(challenge) => return Ok(challenge),
+                PageState::Attended => {
+                    return Err(AttendanceOutcome::AlreadyAttended { attempts: 1 });
+                }
+                PageState::Done(reward) => {
+                    log::debug!("{} 没有题目，访问签到页面即完成签到", self.site.name());
+                    return Err(AttendanceOutcome::Success {
+                        attempts: 1,
+                        answer: None,
+                        reward,
+                    });
+                }
+                // 补签后重新获取签到页面
+                PageState::MakeUp(form) => self.make_up(&form).await?,
+            }
         }
+        let e = anyhow!("{} 补签次数超过 {}", self.config.id(), MAX_MAKEUPS);
+        Err(AttendanceOutcome::failed(Failure::MakeUp, e, None))
+    }
 
-        // 获取结果
-        let mut answers: Vec<_> = answers.into_iter().map(picparser::Answer::from).collect();
-        let mut kaptcha = picparser::Kaptcha::new(img_url);
-
-        let result = tokio::task::block_in_place(move || {
-            tokio::runtime::Handle::current().block_on(async move {
-                kaptcha
-                    .compare_with_answers(&mut answers, &self.client, 93.0)
-                    .await
-            })
-        })?;
-
-        log::info!("结果是: {}", result.name);
+    /// 在预算内补签一次
+    async fn make_up(&self, form: &MakeUp) -> std::result::Result<(), AttendanceOutcome> {
+        let refuse = |e| AttendanceOutcome::failed(Failure::MakeUp, e, None);
+        let id = self.config.id();
+        if !self.makeup.enable() {
+            return Err(refuse(anyhow!("{} 需要补签，但是没有开启补签", id)));
+        }
+        let Some(cost) = form.cost else {
+            return Err(refuse(anyhow!("{} 无法获取补签需要的魔力值，不会补签", id)));
+        };
+        if let Some(bonus) = form.bonus {
+            if bonus < cost {
+                return Err(refuse(anyhow!("{} 魔力值不足以补签: {} < {}", id, bonus, cost)));
+            }
+        }
+        let spent = self.makeup_spent.lock().map(|s| *s).unwrap_or_default();
+        if spent + cost > self.makeup.budget() {
+            return Err(refuse(anyhow!(
+                "{} 补签需要 {} 魔力值，超出预算: 已花费 {}/{}",
+                id,
+                cost,
+                spent,
+                self.makeup.budget()
+            )));
+        }
 
-        tokio::task::block_in_place(move || {
-            tokio::runtime::Handle::current()
-                .block_on(async move { self.post_answer(&result.value).await })
-        })
+        self.site
+            .make_up(&self.client, form)
+            .await
+            .map_err(|e| AttendanceOutcome::failed(Failure::Other, e, None))?;
+        if let Ok(mut spent) = self.makeup_spent.lock() {
+            *spent += cost;
+        }
+        log::info!("{} 补签成功，花费 {} 魔力值", id, cost);
+        Ok(())
     }
 
     /// 签到
@@ -248,78 +422,157 @@ impl TjuPtUser {
     ///
     /// 尝试加载cookie一次
     ///
-    /// 并立即签到
-    pub async fn att_now(&self, enable_email: bool, email_config: Arc<EmailConfig>) -> Result<()> {
+    /// 并立即签到，失败时根据原因决定是否重试
+    pub async fn att_now(
+        &self,
+        enable_email: bool,
+        email_config: Arc<EmailConfig>,
+    ) -> AttendanceOutcome {
         // 这里加载一次cookie就好
         let _res = self.load_cookie();
 
-        let retry_times = self.config.retry();
+        let retry_times = self.config.retry().max(1);
+        let backoff = self.config.backoff();
+        let mut outcome = AttendanceOutcome::AlreadyAttended { attempts: 0 };
         for i in 0..retry_times {
-            // // 为了豆瓣正常访问需要等待
-            // if i > 0 && i < retry_times-1 {
-            //     tokio::time::sleep(Duration::from_secs(10)).await;
-            // }
-
-            if let Err(e) = self.att_onece_now().await {
-                log::debug!(
-                    "{} 签到失败 {}/{} Error: {}",
-                    self.config.id(),
-                    i + 1,
-                    retry_times,
-                    e
-                );
-                continue;
-            } else {
-                log::info!("签到成功: {}", self.config.id());
-                return Ok(());
+            outcome = self.att_onece_now().await.with_attempts(i + 1);
+            if !outcome.retryable() || i + 1 == retry_times {
+                break;
             }
+
+            // 指数退避，服务器要求等待更久时按照 `Retry-After`，但是不超过 `max`
+            let delay = backoff.delay(u32::from(i) + 1, random());
+            let delay = match outcome.retry_after() {
+                Some(after) if after > backoff.max_delay() => {
+                    log::warn!(
+                        "{} 服务器要求 {} 秒后重试，超过了最长的等待时间，不再重试",
+                        self.config.id(),
+                        after.as_secs()
+                    );
+                    break;
+                }
+                Some(after) => delay.max(after),
+                None => delay,
+            };
+            log::debug!(
+                "{} 签到失败 {}/{}，{:.1} 秒后重试: {}",
+                self.config.id(),
+                i + 1,
+                retry_times,
+                delay.as_secs_f64(),
+                outcome
+            );
+            tokio::time::sleep(delay).await;
         }
 
-        if enable_email {
+        match outcome {
+            AttendanceOutcome::Failed { .. } => log::error!("{}: {}", self.config.id(), outcome),
+            _ => log::info!("{}: {}", self.config.id(), outcome),
+        }
+
+        if enable_email && outcome.notify(email_config.on_success()) {
             if let Some(rec) = self.config.email() {
                 if let Err(e) = email_bot::send_email(
                     &email_config,
                     rec,
-                    format!("{} 签到失败", self.config.id()).as_str(),
+                    format!("{} {}", self.config.id(), outcome).as_str(),
                 ) {
                     log::error!("邮件发送失败!, Err: {}", e);
                 }
             }
         }
 
-        Err(anyhow!("签到失败: {}", self.config.id()))
+        outcome
     }
 
-    /// 清除cookie
-    pub fn clear_cookie(&self) -> Result<()> {
+    /// 替换cookie，比如从浏览器导入的
+    pub fn set_cookie(&self, store: CookieStore) -> Result<()> {
         let Ok(mut lock) = self.cookie.lock() else {
             return Err(anyhow!("无法获取锁"));
         };
-        lock.clear();
+        *lock = store;
         Ok(())
     }
 
-    pub fn client(&self) -> &Client {
-        &self.client
+    /// 检查cookie是否有效，不会登陆也不会签到
+    pub async fn check_login(&self) -> Result<bool> {
+        self.site.logged_in(&self.client).await
     }
 
-    async fn post_answer(&self, value: &str) -> Result<()> {
-        let data = &[("answer", value), ("submit", "提交")];
-        let r = self
+    /// 查看登陆状态
+    ///
+    /// 只加载cookie并访问页面，不会登陆，也不会提交答案
+    pub async fn status(&mut self) -> Result<SessionStatus> {
+        let cookie = self.load_cookie().is_ok() && self.cookie_path.is_some();
+        // 只读取cookie，drop 时不写回，也不为没有cookie的用户创建空文件
+        self.cookie_path = None;
+        let expires = self.cookie_expires();
+
+        let res = self
             .client
-            .post(tjurls::ATTENDANCE)
-            .form(data)
+            .get(self.site.status_url().clone())
             .send()
-            .await?
-            .text()
             .await?;
-        if r.contains("签到成功") {
-            Ok(())
+        let logged_in = !self.site.urls().is_login(res.url());
+        let attended = if logged_in {
+            self.site.attended(&res.text().await?)
         } else {
-            Err(anyhow!("签到失败"))
+            None
+        };
+
+        Ok(SessionStatus {
+            user: self.config.id().into(),
+            site: self.site.name().into(),
+            cookie,
+            logged_in,
+            attended,
+            expires,
+        })
+    }
+
+    /// 查询账号信息
+    ///
+    /// 优先使用保存的cookie，失效时会登陆
+    pub async fn info(&self) -> AccountInfo {
+        let _res = self.load_cookie();
+        let (profile, error) = match self.site.profile(&self.client, &self.config).await {
+            Ok(profile) => (profile, None),
+            Err(e) => (Profile::default(), Some(e.to_string())),
+        };
+        AccountInfo {
+            user: self.config.id().into(),
+            site: self.site.name().into(),
+            profile,
+            error,
         }
     }
 
+    /// 站点cookie中最早的过期时间
+    fn cookie_expires(&self) -> Option<String> {
+        let lock = self.cookie.lock().ok()?;
+        lock.matches(self.site.urls().base())
+            .into_iter()
+            .filter_map(|c| match c.expires {
+                CookieExpiration::AtUtc(ref t) => Some(t),
+                CookieExpiration::SessionEnd => None,
+            })
+            .min_by_key(|t| t.unix_timestamp())
+            .map(|t| format!("{} {:02}:{:02} UTC", t.date(), t.hour(), t.minute()))
+    }
+
+    /// 清除cookie
+    pub fn clear_cookie(&self) -> Result<()> {
+        let Ok(mut lock) = self.cookie.lock() else {
+            return Err(anyhow!("无法获取锁"));
+        };
+        lock.clear();
+        Ok(())
+    }
+
+    pub fn client(&self) -> &Session {
+        &self.client
+    }
+
     /// 保存 cookie 到 cookie_path
     ///
     /// 不为 cookiepath 为None 的保存
@@ -390,6 +643,10 @@ pub async fn attendance() -> Result<()> {
     let config_path: &String = mat.get_one("file").unwrap();
 
     let enable_email = mat.get_flag("email");
+    // 命令行中的代理覆盖配置文件
+    let cli_proxy = mat
+        .get_one::<String>("proxy")
+        .map(|url| ProxyConfig::from_url(url));
 
     if mat.get_flag("init") {
         // 如果是初始化
@@ -402,26 +659,38 @@ pub async fn attendance() -> Result<()> {
         let users: Vec<&String> = mat.get_many("user").unwrap().collect();
         let users_num = users.len() / 2;
         let retry: u8 = *mat.get_one("retry").unwrap();
+        let proxy = cli_proxy.unwrap_or_default();
+        let http = HttpConfig::default();
+        let (ratelimit, backoff) = (RateLimitConfig::default(), BackoffConfig::default());
+        let posters =
+            Arc::new(PosterChain::from_config(&[], &http, &proxy, &ratelimit, &backoff)?);
+        let site = Sites::default().get(None)?;
         let mut users_vec = vec![];
         for i in 0..users_num {
             let Some(user_id) = users.get(2*i)
                 else {continue;};
             let Some(user_pwd) = users.get(2*i+1)
                 else {continue;};
-            let user = UserConfig::new(
+            let mut user = UserConfig::new(
                 true,
                 user_id.to_string(),
                 user_pwd.to_string(),
                 None,
                 Some(retry),
             );
+            user.set_proxy(proxy.clone());
 
-            users_vec.push(TjuPtUser::from_config::<&Path>(user, None));
+            users_vec.push(TjuPtUser::from_config::<&Path>(
+                user,
+                None,
+                posters.clone(),
+                site.clone(),
+            )?);
         }
 
         // 开始马上签到
         let email_config = Arc::new(EmailConfig::default());
-        att_all_now(users_vec, false, email_config).await;
+        att_all_now(users_vec, false, email_config).await?;
     } else if let Some(config_mat) = mat.subcommand_matches("config") {
         // 如果是配置文件
         let config_path: &String = config_mat.get_one("file").unwrap();
@@ -473,17 +742,190 @@ pub async fn attendance() -> Result<()> {
             }
             println!("邮件配置信息：{}", config_file.get_email_config());
         }
+    } else if let Some(cache_mat) = mat.subcommand_matches("cache") {
+        // 如果是缓存
+        let config_path: &String = cache_mat.get_one("file").unwrap();
+        let config_path = Path::new(config_path);
+        let cache_config = if config_path.is_file() {
+            ConfigFile::new_from(config_path)?
+                .gloablconfig()
+                .cache()
+                .clone()
+        } else {
+            Default::default()
+        };
+        let cache = PosterCache::new(DIRS.state_dir(), &cache_config);
+
+        if cache_mat.get_flag("clear") {
+            log::info!("删除所有缓存: {}", cache.dir().display());
+            cache.clear()?;
+        }
+        if cache_mat.get_flag("prune") {
+            let removed = cache.prune()?;
+            log::info!("清理缓存: 删除了 {} 个文件", removed);
+        }
+        if cache_mat.get_flag("show") {
+            println!("缓存位置: {}", cache.dir().display());
+            println!("缓存信息: {}", cache.stats()?);
+        }
+    } else if let Some(samples_mat) = mat.subcommand_matches("samples") {
+        // 如果是样本
+        let archive = Archive::new(DIRS.state_dir());
+
+        if let Some(days) = samples_mat.get_one::<u64>("prune") {
+            let removed = archive.prune(*days)?;
+            log::info!("清理样本: 删除了 {} 个", removed);
+        }
+        if samples_mat.get_flag("list") {
+            println!("样本位置: {}", archive.dir().display());
+            let samples = archive.list()?;
+            println!("样本数量: {}", samples.len());
+            for (path, sample) in samples.iter() {
+                println!("    {} {}", path.display(), sample);
+            }
+        }
+    } else if let Some(bench_mat) = mat.subcommand_matches("bench") {
+        // 如果是评估
+        let archive = Archive::new(DIRS.state_dir());
+        let dir = match bench_mat.get_one::<String>("dir") {
+            Some(d) => Path::new(d),
+            None => archive.dir(),
+        };
+        let kinds = match bench_mat.get_many::<String>("matcher") {
+            Some(m) => m.map(|m| m.parse()).collect::<Result<Vec<_>>>()?,
+            None => MatcherKind::ALL.to_vec(),
+        };
+        let config_path: &String = bench_mat.get_one("file").unwrap();
+        let config_path = Path::new(config_path);
+        let captcha = if config_path.is_file() {
+            ConfigFile::new_from(config_path)?
+                .gloablconfig()
+                .captcha()
+                .clone()
+        } else {
+            Default::default()
+        };
+        let limit = bench_mat.get_one::<f64>("limit").copied();
+        let margin = bench_mat.get_one::<f64>("margin").copied();
+        let weight = bench_mat.get_one::<f64>("weight").copied();
+
+        println!("样本位置: {}", dir.display());
+        for kind in kinds.into_iter() {
+            let config =
+                CaptchaConfig::overrides(Some(kind), limit, margin, weight).merge(&captcha);
+            config.validate()?;
+            println!("{}", config);
+            let report = bench::run(dir, &config)?;
+            println!("{}", report);
+        }
+    } else if let Some(status_mat) = mat.subcommand_matches("status") {
+        // 如果是查看登陆状态
+        let config_path: &String = status_mat.get_one("file").unwrap();
+        let config_file = ConfigFile::new_from(config_path)?;
+        let sites = Sites::from_config(config_file.site(), config_file.sites())?;
+        let posters = Arc::new(PosterChain::default());
+
+        let mut users = config_file.get_users();
+        users.sort_by(|a, b| (a.site(), a.id()).cmp(&(b.site(), b.id())));
+        for mut u in users.into_iter() {
+            u.update_proxy(config_file.gloablconfig());
+            u.update_http(config_file.gloablconfig());
+            let id = u.id().to_string();
+            let site = sites.get(u.site())?;
+            let mut user =
+                TjuPtUser::from_config(u, Some(DIRS.state_dir()), posters.clone(), site)?;
+            match user.status().await {
+                Ok(status) => println!("{}", status),
+                Err(e) => println!("{}: 无法访问站点, Err: {}", id, e),
+            }
+        }
+    } else if let Some(info_mat) = mat.subcommand_matches("info") {
+        // 如果是查询账号信息
+        let config_path: &String = info_mat.get_one("file").unwrap();
+        let config_file = ConfigFile::new_from(config_path)?;
+        let sites = Sites::from_config(config_file.site(), config_file.sites())?;
+        let posters = Arc::new(PosterChain::default());
+
+        let mut users = config_file.get_users();
+        users.retain(|u| u.enable());
+        users.sort_by(|a, b| (a.site(), a.id()).cmp(&(b.site(), b.id())));
+        let mut infos = vec![];
+        for mut u in users.into_iter() {
+            u.update_proxy(config_file.gloablconfig());
+            u.update_http(config_file.gloablconfig());
+            let site = sites.get(u.site())?;
+            let user = TjuPtUser::from_config(u, Some(DIRS.state_dir()), posters.clone(), site)?;
+            infos.push(user.info().await);
+        }
+
+        if info_mat.get_flag("json") {
+            println!("{}", serde_json::to_string_pretty(&infos)?);
+        } else {
+            AccountInfo::print_table(&infos);
+        }
+    } else if let Some(cookie_mat) = mat.subcommand_matches("cookie") {
+        // 如果是cookie
+        if let Some(import_mat) = cookie_mat.subcommand_matches("import") {
+            import_cookie(import_mat).await?;
+        }
     } else {
         // 其他情况，使用配置文件直接运行
         let config_file = ConfigFile::new_from(config_path)?;
         let g_conf = config_file.gloablconfig();
+        let proxy = cli_proxy.as_ref().unwrap_or(g_conf.proxy());
+        let mut posters = PosterChain::from_config(
+            g_conf.posters(),
+            g_conf.http(),
+            proxy,
+            g_conf.ratelimit(),
+            g_conf.backoff(),
+        )?;
+        if g_conf.cache().enable() {
+            let cache = PosterCache::new(DIRS.state_dir(), g_conf.cache());
+            if let Err(e) = cache.prune() {
+                log::warn!("无法清理缓存: {}", e);
+            }
+            posters = posters.with_cache(cache);
+        }
+        let posters = Arc::new(posters);
+        let sites = Sites::from_config(config_file.site(), config_file.sites())?;
+        let archive = g_conf
+            .archive()
+            .enable()
+            .then(|| Arc::new(Archive::new(DIRS.state_dir())));
         let users = config_file
             .get_users()
             .into_iter()
             .filter_map(|mut u| {
                 u.update_retry(g_conf);
+                u.update_captcha(g_conf);
+                u.update_proxy(g_conf);
+                u.update_http(g_conf);
+                u.update_backoff(g_conf);
+                if let Some(ref proxy) = cli_proxy {
+                    u.set_proxy(proxy.clone());
+                }
                 if u.enable() {
-                    Some(TjuPtUser::from_config(u, Some(DIRS.state_dir())))
+                    let site = match sites.get(u.site()) {
+                        Ok(site) => site,
+                        Err(e) => {
+                            log::error!("{} {}", u.id(), e);
+                            return None;
+                        }
+                    };
+                    let user =
+                        TjuPtUser::from_config(u, Some(DIRS.state_dir()), posters.clone(), site);
+                    let user = match user {
+                        Ok(user) => user.with_makeup(g_conf.makeup().clone()),
+                        Err(e) => {
+                            log::error!("{}", e);
+                            return None;
+                        }
+                    };
+                    Some(match archive {
+                        Some(ref archive) => user.with_archive(archive.clone()),
+                        None => user,
+                    })
                 } else {
                     None
                 }
@@ -492,13 +934,66 @@ pub async fn attendance() -> Result<()> {
 
         // 签到
         let email_config = Arc::new(config_file.get_email_config());
-        att_all_now(users, enable_email, email_config).await;
+        att_all_now(users, enable_email, email_config).await?;
     }
     Ok(())
 }
 
+/// 导入浏览器的cookie
+///
+/// 先用不保存cookie的实例检查，有效后再保存到用户的cookie文件
+async fn import_cookie(mat: &ArgMatches) -> Result<()> {
+    let config_path: &String = mat.get_one("file").unwrap();
+    let config_file = ConfigFile::new_from(config_path)?;
+    let id: &String = mat.get_one("user").unwrap();
+    let site_name = mat.get_one::<String>("site").map(String::as_str);
+    let Some(mut user) = config_file
+        .get_users()
+        .into_iter()
+        .find(|u| u.id() == id && u.site() == site_name)
+    else {
+        return Err(anyhow!("配置文件中不存在用户: {}", id));
+    };
+    user.update_proxy(config_file.gloablconfig());
+    user.update_http(config_file.gloablconfig());
+    let site = Sites::from_config(config_file.site(), config_file.sites())?.get(user.site())?;
+
+    let input: &String = mat.get_one("input").unwrap();
+    let content = if input == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(input)?
+    };
+    let cookies = cookies::parse(&content)?;
+    let (store, imported) = cookies::to_store(&cookies, site.urls().base())?;
+    log::info!("解析到 {} 个cookie，导入 {} 个", cookies.len(), imported);
+
+    let posters = Arc::new(PosterChain::default());
+    if !mat.get_flag("no-check") {
+        let checker =
+            TjuPtUser::from_config::<&Path>(user.clone(), None, posters.clone(), site.clone())?;
+        checker.set_cookie(store.clone())?;
+        if !checker.check_login().await? {
+            return Err(anyhow!("cookie无效，被重定向到了登陆页面，没有保存"));
+        }
+        log::info!("cookie有效: {} {}", site.name(), id);
+    }
+
+    let user = TjuPtUser::from_config(user, Some(DIRS.state_dir()), posters, site)?;
+    user.set_cookie(store)?;
+    user.save_cookie()?;
+    log::info!("保存cookie成功: {}", id);
+    Ok(())
+}
+
 /// 批量签到
-async fn att_all_now(users: Vec<TjuPtUser>, enable_email: bool, email_config: Arc<EmailConfig>) {
+///
+/// 有用户签到失败时返回错误，程序以非零的退出码结束
+async fn att_all_now(
+    users: Vec<TjuPtUser>,
+    enable_email: bool,
+    email_config: Arc<EmailConfig>,
+) -> Result<()> {
     // 签到
     let mut hands = vec![];
     for i in users.into_iter() {
@@ -508,16 +1003,22 @@ async fn att_all_now(users: Vec<TjuPtUser>, enable_email: bool, email_config: Ar
         }));
     }
 
+    let mut failed = 0;
     for i in hands.into_iter() {
-        let Ok(i) = i.await else {
-            continue;
-        };
-
-        if let Err(e) = i {
-            log::error!("{}", e);
-            continue;
+        match i.await {
+            Ok(outcome) if !outcome.is_failure() => {}
+            Ok(_) => failed += 1,
+            Err(e) => {
+                log::error!("签到任务异常: {}", e);
+                failed += 1;
+            }
         }
     }
+
+    if failed > 0 {
+        return Err(anyhow!("{} 个用户签到失败", failed));
+    }
+    Ok(())
 }
 
 /// 从user——vec转users
@@ -537,3 +1038,13 @@ fn get_users_vec(users: Vec<&str>) -> Vec<(&str, &str)> {
     }
     users_res
 }
+
+/// `[0, 1)` 中的随机数，用于重试间隔的抖动
+fn random() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    // 每个 `RandomState` 的密钥不同
+    let hash = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,470 @@
+//! 海报缓存
+//!
+//! 保存在状态文件夹里，目录结构:
+//! - `titles/`: 选项名字 -> 海报信息
+//! - `urls/`: 海报链接 -> 图片内容的哈希
+//! - `blobs/`: 以内容哈希命名的图片
+
+use crate::{
+    config::CacheConfig,
+    poster::{PosterImage, PosterMeta},
+};
+use ahash::AHashMap;
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TITLES: &str = "titles";
+const URLS: &str = "urls";
+const BLOBS: &str = "blobs";
+
+/// 海报缓存
+#[derive(Debug)]
+pub struct PosterCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TitleEntry {
+    title: String,
+    /// 候选海报，按相关程度排列
+    metas: Vec<PosterMeta>,
+    time: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UrlEntry {
+    url: String,
+    blob: String,
+    /// 下载时的 `Content-Type`
+    #[serde(default)]
+    content_type: Option<String>,
+    time: u64,
+}
+
+/// 缓存的统计信息
+pub struct CacheStats {
+    pub titles: usize,
+    pub urls: usize,
+    pub blobs: usize,
+    /// 图片的总大小
+    pub size: u64,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cache[titles: {} urls: {} blobs: {} size: {:.2}MB]",
+            self.titles,
+            self.urls,
+            self.blobs,
+            self.size as f64 / 1024.0 / 1024.0
+        )
+    }
+}
+
+impl PosterCache {
+    /// 在 `state_dir` 下创建缓存
+    pub fn new<P>(state_dir: P, config: &CacheConfig) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            dir: state_dir.as_ref().join("posters"),
+            ttl: Duration::from_secs(config.ttl() * 24 * 60 * 60),
+            max_size: config.max_size() * 1024 * 1024,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 根据选项名字获取候选海报的信息
+    pub fn get_metas(&self, title: &str) -> Option<Vec<PosterMeta>> {
+        let entry: TitleEntry = self.read_entry(TITLES, &normalize_title(title))?;
+        self.is_fresh(entry.time).then_some(entry.metas)
+    }
+
+    /// 保存候选海报的信息
+    pub fn put_metas(&self, title: &str, metas: &[PosterMeta]) -> Result<()> {
+        let entry = TitleEntry {
+            title: title.into(),
+            metas: metas.to_vec(),
+            time: now(),
+        };
+        self.write_entry(TITLES, &normalize_title(title), &entry)
+    }
+
+    /// 根据海报链接获取图片
+    pub fn get_poster(&self, url: &str) -> Option<PosterImage> {
+        let entry: UrlEntry = self.read_entry(URLS, url)?;
+        if !self.is_fresh(entry.time) {
+            return None;
+        }
+        let b = fs::read(self.dir.join(BLOBS).join(entry.blob)).ok()?;
+        Some(PosterImage {
+            bytes: b.into(),
+            content_type: entry.content_type,
+        })
+    }
+
+    /// 保存图片
+    pub fn put_poster(&self, url: &str, img: &PosterImage) -> Result<()> {
+        let blob = hash(&img.bytes);
+        let blobs = self.dir.join(BLOBS);
+        fs::create_dir_all(&blobs)?;
+        let blob_path = blobs.join(&blob);
+        if !blob_path.is_file() {
+            write_atomic(&blob_path, &img.bytes)?;
+        }
+
+        let entry = UrlEntry {
+            url: url.into(),
+            blob,
+            content_type: img.content_type.clone(),
+            time: now(),
+        };
+        self.write_entry(URLS, url, &entry)
+    }
+
+    /// 统计信息
+    pub fn stats(&self) -> Result<CacheStats> {
+        let blobs = list_files(&self.dir.join(BLOBS))?;
+        Ok(CacheStats {
+            titles: list_files(&self.dir.join(TITLES))?.len(),
+            urls: list_files(&self.dir.join(URLS))?.len(),
+            size: blobs.iter().map(|(_, len)| len).sum(),
+            blobs: blobs.len(),
+        })
+    }
+
+    /// 清理缓存
+    ///
+    /// 删除过期的条目、没有被引用的图片，
+    /// 如果图片总大小超过限制，从最旧的开始删除
+    ///
+    /// 返回删除的文件数量
+    pub fn prune(&self) -> Result<usize> {
+        let mut removed = 0;
+
+        for (path, _) in list_files(&self.dir.join(TITLES))? {
+            match read_json::<TitleEntry>(&path) {
+                Some(e) if self.is_fresh(e.time) => {}
+                _ => {
+                    fs::remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        let mut urls = vec![];
+        for (path, _) in list_files(&self.dir.join(URLS))? {
+            match read_json::<UrlEntry>(&path) {
+                Some(e)
+                    if self.is_fresh(e.time) && self.dir.join(BLOBS).join(&e.blob).is_file() =>
+                {
+                    urls.push((path, e))
+                }
+                _ => {
+                    fs::remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        // 超过大小限制时，从最旧的开始删除
+        // 图片可能被多个链接共用，只计算一次，最后一个引用被删除时才减去
+        let blob_size = |blob: &str| {
+            fs::metadata(self.dir.join(BLOBS).join(blob))
+                .map(|m| m.len())
+                .unwrap_or_default()
+        };
+        let mut refs: AHashMap<String, usize> = AHashMap::new();
+        for (_, e) in urls.iter() {
+            *refs.entry(e.blob.clone()).or_default() += 1;
+        }
+        let mut size: u64 = refs.keys().map(|b| blob_size(b)).sum();
+        urls.sort_by_key(|(_, e)| e.time);
+        let mut urls = urls.into_iter();
+        while size > self.max_size {
+            let Some((path, e)) = urls.next() else {
+                break;
+            };
+            fs::remove_file(&path)?;
+            removed += 1;
+            if let Some(n) = refs.get_mut(&e.blob) {
+                *n -= 1;
+                if *n == 0 {
+                    refs.remove(&e.blob);
+                    size = size.saturating_sub(blob_size(&e.blob));
+                }
+            }
+        }
+
+        for (path, _) in list_files(&self.dir.join(BLOBS))? {
+            let referenced = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| refs.contains_key(n))
+                .unwrap_or_default();
+            if !referenced {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// 删除所有缓存
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.is_dir() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// 是否还没过期
+    fn is_fresh(&self, time: u64) -> bool {
+        now().saturating_sub(time) <= self.ttl.as_secs()
+    }
+
+    fn read_entry<T: DeserializeOwned>(&self, kind: &str, key: &str) -> Option<T> {
+        let path = self
+            .dir
+            .join(kind)
+            .join(format!("{}.json", hash(key.as_bytes())));
+        read_json(&path)
+    }
+
+    fn write_entry<T: Serialize>(&self, kind: &str, key: &str, entry: &T) -> Result<()> {
+        let dir = self.dir.join(kind);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", hash(key.as_bytes())));
+        write_atomic(&path, &serde_json::to_vec(entry)?)
+    }
+}
+
+/// 规范化选项名字：去除空白，转小写
+pub(crate) fn normalize_title(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn hash(b: &[u8]) -> String {
+    Sha256::digest(b)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let file = File::open(path).map(BufReader::new).ok()?;
+    serde_json::from_reader(file).ok()
+}
+
+/// 先写入临时文件再重命名，避免并发时读到一半的文件
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp = path.with_extension(format!(
+        "tmp{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// 文件夹中的文件及其大小，文件夹不存在时返回空
+fn list_files(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() {
+            files.push((entry.path(), meta.len()));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+    use bytes::Bytes;
+
+    /// 临时文件夹中的缓存，有效期 60 秒，图片总大小上限 10 字节
+    fn temp_cache(name: &str) -> PosterCache {
+        let dir =
+            std::env::temp_dir().join(format!("tjuptatt_cache_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        PosterCache {
+            dir,
+            ttl: Duration::from_secs(60),
+            max_size: 10,
+        }
+    }
+
+    fn image(b: &[u8], content_type: Option<&str>) -> PosterImage {
+        PosterImage {
+            bytes: Bytes::copy_from_slice(b),
+            content_type: content_type.map(String::from),
+        }
+    }
+
+    /// 保存图片，并把条目的时间改为 `time`
+    fn put_poster_at(cache: &PosterCache, url: &str, b: &[u8], time: u64) {
+        cache.put_poster(url, &image(b, None)).unwrap();
+        let entry = UrlEntry {
+            url: url.into(),
+            blob: hash(b),
+            content_type: None,
+            time,
+        };
+        cache.write_entry(URLS, url, &entry).unwrap();
+    }
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(normalize_title(" The  Matrix "), "thematrix");
+        assert_eq!(normalize_title("肖申克的 救赎"), "肖申克的救赎");
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let cache = temp_cache("round_trip");
+        let url = "https://example.com/a.jpg";
+        assert!(cache.get_poster(url).is_none());
+        cache
+            .put_poster(url, &image(b"poster", Some("image/webp")))
+            .unwrap();
+        let cached = cache.get_poster(url).unwrap();
+        assert_eq!(&cached.bytes[..], b"poster");
+        assert_eq!(cached.content_type.as_deref(), Some("image/webp"));
+
+        // 内容相同的图片只保存一份
+        let other = "https://example.com/b.jpg";
+        cache.put_poster(other, &image(b"poster", None)).unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.urls, stats.blobs, stats.size), (2, 1, 6));
+
+        let meta = PosterMeta {
+            title: "The Matrix".into(),
+            sub_title: None,
+            year: Some("1999".into()),
+            url: url.into(),
+        };
+        cache.put_metas("The Matrix", &[meta]).unwrap();
+        let metas = cache.get_metas(" the matrix").unwrap();
+        assert_eq!(metas.len(), 1);
+        assert_eq!(metas[0].url, url);
+
+        cache.clear().unwrap();
+        assert!(!cache.dir().exists());
+    }
+
+    #[test]
+    fn ttl_test() {
+        let cache = temp_cache("ttl");
+        let old = now() - 120;
+        let entry = TitleEntry {
+            title: "old".into(),
+            metas: vec![],
+            time: old,
+        };
+        cache.write_entry(TITLES, "old", &entry).unwrap();
+        assert!(cache.get_metas("old").is_none());
+
+        put_poster_at(&cache, "old", b"old", old);
+        assert!(cache.get_poster("old").is_none());
+        put_poster_at(&cache, "fresh", b"fresh", now());
+        assert!(cache.get_poster("fresh").is_some());
+
+        // 过期的两个条目及只被过期条目引用的图片
+        assert_eq!(cache.prune().unwrap(), 3);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.titles, stats.urls, stats.blobs), (0, 1, 1));
+        assert!(cache.get_poster("fresh").is_some());
+
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn prune_test() {
+        let cache = temp_cache("prune");
+        let t = now();
+        put_poster_at(&cache, "a", b"aaaaaa", t - 3);
+        put_poster_at(&cache, "b", b"bbbbbb", t - 2);
+        put_poster_at(&cache, "c", b"cccccc", t - 1);
+
+        // 没有被引用的图片
+        fs::write(cache.dir().join(BLOBS).join("orphan"), b"orphan").unwrap();
+        // 图片已经不存在的链接
+        let missing = UrlEntry {
+            url: "missing".into(),
+            blob: hash(b"missing"),
+            content_type: None,
+            time: t,
+        };
+        cache.write_entry(URLS, "missing", &missing).unwrap();
+
+        // 总大小 18 字节，超过 10 字节，删除最旧的 a 和 b
+        // 链接: missing、a、b，图片: a、b、orphan
+        assert_eq!(cache.prune().unwrap(), 6);
+        assert!(cache.get_poster("a").is_none());
+        assert!(cache.get_poster("b").is_none());
+        assert_eq!(&cache.get_poster("c").unwrap().bytes[..], b"cccccc");
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.urls, stats.blobs, stats.size), (1, 1, 6));
+
+        // 没有需要清理的
+        assert_eq!(cache.prune().unwrap(), 0);
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn shared_blob_test() {
+        let cache = temp_cache("shared_blob");
+        let t = now();
+        put_poster_at(&cache, "a", b"aaaaaa", t - 3);
+        // b 和 c 共用一张图片
+        put_poster_at(&cache, "b", b"shared", t - 2);
+        put_poster_at(&cache, "c", b"shared", t - 1);
+
+        // 不重复的图片共 12 字节，只需要删除最旧的 a
+        assert_eq!(cache.prune().unwrap(), 2);
+        assert!(cache.get_poster("a").is_none());
+        assert!(cache.get_poster("b").is_some());
+        assert!(cache.get_poster("c").is_some());
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.urls, stats.blobs, stats.size), (2, 1, 6));
+
+        cache.clear().unwrap();
+    }
+}
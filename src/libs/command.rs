@@ -2,21 +2,12 @@
 use clap::crate_name;
 use lazy_static::lazy_static;
 use platform_dirs::AppDirs;
+use reqwest::header::HeaderMap;
 use std::path::{Path, PathBuf};
 
 /// 配置文件名
 pub const CONFIG_FILENAME: &str = "config.toml";
 
-/// 链接们
-pub mod tjurls {
-    /// 登陆链接
-    pub const LOGIN: &str = "https://tjupt.org/login.php";
-    /// 签到页面
-    pub const ATTENDANCE: &str = "https://tjupt.org/attendance.php";
-    /// post的登陆url
-    pub const TAKELOGIN: &str = "https://tjupt.org/takelogin.php";
-}
-
 /// 文件位置配置
 pub struct Dirs {
     config_dir: PathBuf,
@@ -39,6 +30,20 @@ impl Dirs {
 }
 
 lazy_static! {
+    /// 默认的请求头
+    pub static ref HEADER: HeaderMap = {
+        let mut head = HeaderMap::new();
+        head.insert(
+            "User-Agent",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) \
+            AppleWebKit/537.36 (KHTML, like Gecko) \
+            Chrome/100.0.0.0 Safari/537.36"
+                .parse()
+                .unwrap(),
+        );
+        head
+    };
+
     pub static ref DIRS: Dirs = {
         let appdir = AppDirs::new(Some(crate_name!()), false).expect("无法获取默认配置文件");
 
@@ -4,10 +4,13 @@ use std::{
     fs::{read_to_string, File},
     hash::Hash,
     io::Write,
+    net::IpAddr,
     path::Path,
+    str::FromStr,
+    time::Duration,
 };
 
-use ahash::AHashSet;
+use ahash::{AHashMap, AHashSet};
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use toml;
@@ -16,6 +19,11 @@ use toml;
 pub struct ConfigFile {
     users: AHashSet<UserConfig>,
     global: GlobalConfig,
+    #[serde(default)]
+    site: SiteConfig,
+    /// 其他站点，用户通过名字选择
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sites: Vec<SiteConfig>,
 }
 
 impl ConfigFile {
@@ -41,10 +49,90 @@ impl ConfigFile {
             请使用`--help`查看更多信息",
             path.display()
         ))?;
-        let result = toml::from_str(&content)?;
+        let result: Self = toml::from_str(&content)?;
+        result.validate()?;
         Ok(result)
     }
 
+    /// 检查验证码及站点设置
+    fn validate(&self) -> Result<()> {
+        self.site
+            .validate()
+            .map_err(|e| anyhow!("[site] 设置有误: {}", e))?;
+        let mut names = AHashSet::new();
+        let _r = names.insert(self.site.name());
+        for s in self.sites.iter() {
+            let Some(ref name) = s.name else {
+                return Err(anyhow!("[[sites]] 必须设置 name"));
+            };
+            if s.base_url.is_none() {
+                return Err(anyhow!("站点 {} 必须设置 base_url", name));
+            }
+            s.validate()
+                .map_err(|e| anyhow!("站点 {} 设置有误: {}", name, e))?;
+            if !names.insert(name) {
+                return Err(anyhow!("站点名字重复: {}", name));
+            }
+        }
+        for u in self.users.iter() {
+            if let Some(site) = u.site() {
+                if !names.contains(site) {
+                    return Err(anyhow!("用户 {} 的站点不存在: {}", u.id(), site));
+                }
+            }
+        }
+
+        self.global
+            .captcha
+            .validate()
+            .map_err(|e| anyhow!("[global.captcha] 设置有误: {}", e))?;
+        self.global
+            .makeup
+            .validate()
+            .map_err(|e| anyhow!("[global.makeup] 设置有误: {}", e))?;
+        self.global
+            .proxy
+            .validate()
+            .map_err(|e| anyhow!("[global.proxy] 设置有误: {}", e))?;
+        self.global
+            .http
+            .validate()
+            .map_err(|e| anyhow!("[global.http] 设置有误: {}", e))?;
+        self.global
+            .ratelimit
+            .validate()
+            .map_err(|e| anyhow!("[global.ratelimit] 设置有误: {}", e))?;
+        self.global
+            .backoff
+            .validate()
+            .map_err(|e| anyhow!("[global.backoff] 设置有误: {}", e))?;
+        for u in self.users.iter() {
+            if let Some(ref c) = u.captcha {
+                c.validate()
+                    .map_err(|e| anyhow!("用户 {} 的验证码设置有误: {}", u.id(), e))?;
+            }
+            if let Some(ref p) = u.proxy {
+                p.merge(&self.global.proxy)
+                    .validate()
+                    .map_err(|e| anyhow!("用户 {} 的代理设置有误: {}", u.id(), e))?;
+            }
+            if let Some(ref h) = u.http {
+                h.merge(&self.global.http)
+                    .validate()
+                    .map_err(|e| anyhow!("用户 {} 的HTTP设置有误: {}", u.id(), e))?;
+            }
+            if let Some(ref b) = u.backoff {
+                b.merge(&self.global.backoff)
+                    .validate()
+                    .map_err(|e| anyhow!("用户 {} 的重试间隔设置有误: {}", u.id(), e))?;
+            }
+            if let Some(ref secret) = u.totp {
+                crate::login::totp(secret, 0).map_err(|e| anyhow!("用户 {} 的 {}", u.id(), e))?;
+            }
+        }
+        Ok(())
+    }
+
     /// 用户配置
     pub fn users(&self) -> &AHashSet<UserConfig> {
         &self.users
@@ -81,6 +169,16 @@ impl ConfigFile {
         &self.global
     }
 
+    /// 默认站点的设置
+    pub fn site(&self) -> &SiteConfig {
+        &self.site
+    }
+
+    /// 其他站点的设置
+    pub fn sites(&self) -> &[SiteConfig] {
+        &self.sites
+    }
+
     /// 增加用户
     pub fn addusers(&mut self, users: Vec<UserConfig>) {
         for i in users.into_iter() {
@@ -90,17 +188,15 @@ impl ConfigFile {
 
     /// 删除用户
     pub fn rmusers(&mut self, users: Vec<&str>) {
-        let users: AHashSet<_> = users
-            .into_iter()
-            .map(|u| UserConfig::new(true, u.into(), "".into(), None, None))
-            .collect();
-
-        for i in users.into_iter() {
-            if self.users.remove(&i) {
+        // 所有站点的同名用户都会被删除
+        for id in users.into_iter() {
+            let before = self.users.len();
+            self.users.retain(|u| u.id() != id);
+            if self.users.len() < before {
                 // 如果之前存在
-                log::debug!("成功删除: {}", i.id());
+                log::debug!("成功删除: {}", id);
             } else {
-                log::debug!("无法删除不存在的: {}", i.id());
+                log::debug!("无法删除不存在的: {}", id);
             }
         }
     }
@@ -111,10 +207,11 @@ impl Display for ConfigFile {
         let enable_num = self.users.iter().filter(|u| u.enable).count();
         write!(
             f,
-            "ConfigFile[users: {}/{} {}]",
+            "ConfigFile[users: {}/{} {} {}]",
             enable_num,
             self.users.len(),
-            self.global
+            self.global,
+            self.site
         )
     }
 }
@@ -124,28 +221,54 @@ impl Default for ConfigFile {
         let mut users = AHashSet::new();
         let _r = users.insert(UserConfig::default());
         let global = GlobalConfig::default();
-        Self { users, global }
+        Self {
+            users,
+            global,
+            site: SiteConfig::default(),
+            sites: vec![],
+        }
     }
 }
 
 /// 用户配置信息
-#[derive(Serialize, Deserialize, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserConfig {
     enable: bool,
     id: String,
     pwd: String,
     email: Option<String>,
     retry: Option<u8>,
+    /// 覆盖全局的验证码设置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    captcha: Option<CaptchaConfig>,
+    /// 站点的名字，不指定则使用 `[site]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    site: Option<String>,
+    /// 两步验证的密钥(base32)，登陆需要两步验证码时使用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<String>,
+    /// 覆盖全局的代理设置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<ProxyConfig>,
+    /// 覆盖全局的HTTP客户端设置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http: Option<HttpConfig>,
+    /// 覆盖全局的重试间隔设置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backoff: Option<BackoffConfig>,
 }
 
 impl PartialEq for UserConfig {
     fn eq(&self, other: &Self) -> bool {
-        self.id() == other.id()
+        self.site() == other.site() && self.id() == other.id()
     }
 }
 
+impl Eq for UserConfig {}
+
 impl Hash for UserConfig {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.site().hash(state);
         self.id().hash(state);
     }
 }
@@ -179,6 +302,71 @@ impl UserConfig {
         })
     }
 
+    /// 更新验证码设置
+    ///
+    /// 用户没有单独设置的项使用全局设置
+    pub fn update_captcha(&mut self, global_conf: &GlobalConfig) {
+        self.captcha = Some(match self.captcha {
+            Some(ref c) => c.merge(global_conf.captcha()),
+            None => global_conf.captcha().clone(),
+        })
+    }
+
+    /// 更新代理设置
+    ///
+    /// 用户没有单独设置的项使用全局设置
+    pub fn update_proxy(&mut self, global_conf: &GlobalConfig) {
+        self.proxy = Some(match self.proxy {
+            Some(ref p) => p.merge(global_conf.proxy()),
+            None => global_conf.proxy().clone(),
+        })
+    }
+
+    /// 替换代理设置，比如命令行中指定的
+    pub fn set_proxy(&mut self, proxy: ProxyConfig) {
+        self.proxy = Some(proxy);
+    }
+
+    /// 代理设置，未设置时不使用代理
+    pub fn proxy(&self) -> ProxyConfig {
+        self.proxy.clone().unwrap_or_default()
+    }
+
+    /// 更新HTTP客户端设置
+    ///
+    /// 用户没有单独设置的项使用全局设置
+    pub fn update_http(&mut self, global_conf: &GlobalConfig) {
+        self.http = Some(match self.http {
+            Some(ref h) => h.merge(global_conf.http()),
+            None => global_conf.http().clone(),
+        })
+    }
+
+    /// HTTP客户端设置，未设置时使用默认值
+    pub fn http(&self) -> HttpConfig {
+        self.http.clone().unwrap_or_default()
+    }
+
+    /// 更新重试间隔设置
+    ///
+    /// 用户没有单独设置的项使用全局设置
+    pub fn update_backoff(&mut self, global_conf: &GlobalConfig) {
+        self.backoff = Some(match self.backoff {
+            Some(ref b) => b.merge(global_conf.backoff()),
+            None => global_conf.backoff().clone(),
+        })
+    }
+
+    /// 重试间隔设置，未设置时使用默认值
+    pub fn backoff(&self) -> BackoffConfig {
+        self.backoff.clone().unwrap_or_default()
+    }
+
+    /// 验证码设置，未设置时使用默认值
+    pub fn captcha(&self) -> CaptchaConfig {
+        self.captcha.clone().unwrap_or_default()
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -187,6 +375,16 @@ impl UserConfig {
         self.enable
     }
 
+    /// 站点的名字，`None` 表示 `[site]`
+    pub fn site(&self) -> Option<&str> {
+        self.site.as_deref()
+    }
+
+    /// 两步验证的密钥
+    pub fn totp(&self) -> Option<&str> {
+        self.totp.as_deref()
+    }
+
     /// 新建一个
     ///
     /// 如果是从命令行读取的，那么肯定是开启的
@@ -203,6 +401,12 @@ impl UserConfig {
             pwd,
             email,
             retry,
+            captcha: None,
+            site: None,
+            totp: None,
+            proxy: None,
+            http: None,
+            backoff: None,
         }
     }
 
@@ -213,7 +417,10 @@ impl UserConfig {
 
 impl Display for UserConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "User[{}-{}]", self.id, self.enable)
+        match self.site {
+            None => write!(f, "User[{}-{}]", self.id, self.enable),
+            Some(ref site) => write!(f, "User[{}@{}-{}]", self.id, site, self.enable),
+        }
     }
 }
 
@@ -225,6 +432,12 @@ impl Default for UserConfig {
             pwd: "pwd".into(),
             email: None,
             retry: None,
+            captcha: None,
+            site: None,
+            totp: None,
+            proxy: None,
+            http: None,
+            backoff: None,
         }
     }
 }
@@ -237,6 +450,9 @@ pub struct EmailConfig {
     sender: Option<String>,
     port: Option<u32>,
     host: Option<String>,
+    /// 签到成功时也发送邮件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_success: Option<bool>,
 }
 
 impl EmailConfig {
@@ -267,6 +483,11 @@ impl EmailConfig {
     pub fn pwd(&self) -> &str {
         &self.pwd
     }
+
+    /// 签到成功时是否也发送邮件，默认关闭
+    pub fn on_success(&self) -> bool {
+        self.on_success.unwrap_or(false)
+    }
 }
 
 impl Display for EmailConfig {
@@ -283,15 +504,795 @@ impl Default for EmailConfig {
             sender: None,
             port: None,
             host: None,
+            on_success: None,
+        }
+    }
+}
+
+/// 相似度的计算方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatcherKind {
+    #[default]
+    Dssim,
+    Phash,
+    Dhash,
+    /// 按权重组合 dssim 与 phash
+    Ensemble,
+}
+
+impl MatcherKind {
+    pub const ALL: [MatcherKind; 4] = [Self::Dssim, Self::Phash, Self::Dhash, Self::Ensemble];
+
+    /// 默认的相似度阈值
+    ///
+    /// 各种方式的百分比不在同一个尺度上：哈希按汉明距离换算，不相关的图片也有 50 左右，
+    /// 同一张海报经过裁剪、缩放及压缩后通常在 85 以上。可以用 `bench` 子命令在自己的样本上比较
+    pub fn default_threshold(self) -> f64 {
+        match self {
+            Self::Dssim => 93.0,
+            Self::Phash => 80.0,
+            Self::Dhash => 82.0,
+            Self::Ensemble => 85.0,
+        }
+    }
+}
+
+impl FromStr for MatcherKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|k| k.to_string() == s)
+            .ok_or_else(|| anyhow!("未知的相似度计算方式: {}", s))
+    }
+}
+
+impl Display for MatcherKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Dssim => "dssim",
+            Self::Phash => "phash",
+            Self::Dhash => "dhash",
+            Self::Ensemble => "ensemble",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 缩放图片时使用的滤波器
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    #[default]
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Display for ResizeFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::CatmullRom => "catmullrom",
+            Self::Gaussian => "gaussian",
+            Self::Lanczos3 => "lanczos3",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 验证码设置
+///
+/// 所有项都是可选的，用户的设置会逐项覆盖全局设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CaptchaConfig {
+    /// 最佳选项的相似度阈值(百分比)
+    threshold: Option<f64>,
+    /// 最佳选项至少要领先第二名多少(百分比)，否则认为结果不明确
+    margin: Option<f64>,
+    /// 相似度的计算方式
+    matcher: Option<MatcherKind>,
+    /// `ensemble` 模式下 dssim 的权重，phash 的权重为 `1 - ensemble_weight`
+    ensemble_weight: Option<f64>,
+    /// DSSIM 每个尺度的权重
+    scales: Option<Vec<f64>>,
+    /// 缩放后的宽度
+    width: Option<u32>,
+    /// 缩放后的高度
+    height: Option<u32>,
+    /// 缩放时使用的滤波器
+    filter: Option<ResizeFilter>,
+    /// 是否保持宽高比，不足的部分用白色填充
+    keep_aspect: Option<bool>,
+    /// 每个选项最多比较几张候选海报
+    candidates: Option<usize>,
+}
+
+impl CaptchaConfig {
+    /// 缩放后的最大边长
+    const MAX_SIDE: u32 = 2000;
+    /// 候选海报的最大数量
+    const MAX_CANDIDATES: usize = 10;
+
+    /// 只设置了部分项，用于命令行覆盖
+    pub fn overrides(
+        matcher: Option<MatcherKind>,
+        threshold: Option<f64>,
+        margin: Option<f64>,
+        ensemble_weight: Option<f64>,
+    ) -> Self {
+        Self {
+            threshold,
+            margin,
+            matcher,
+            ensemble_weight,
+            ..Default::default()
+        }
+    }
+
+    /// 逐项合并，自己没有设置的项使用 `other` 的
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            threshold: self.threshold.or(other.threshold),
+            margin: self.margin.or(other.margin),
+            matcher: self.matcher.or(other.matcher),
+            ensemble_weight: self.ensemble_weight.or(other.ensemble_weight),
+            scales: self.scales.clone().or_else(|| other.scales.clone()),
+            width: self.width.or(other.width),
+            height: self.height.or(other.height),
+            filter: self.filter.or(other.filter),
+            keep_aspect: self.keep_aspect.or(other.keep_aspect),
+            candidates: self.candidates.or(other.candidates),
+        }
+    }
+
+    /// 检查设置的值是否合理
+    pub fn validate(&self) -> Result<()> {
+        if let Some(t) = self.threshold {
+            if !(0.0..=100.0).contains(&t) {
+                return Err(anyhow!("threshold 必须在 0 到 100 之间: {}", t));
+            }
+        }
+        if let Some(m) = self.margin {
+            if !(0.0..=100.0).contains(&m) {
+                return Err(anyhow!("margin 必须在 0 到 100 之间: {}", m));
+            }
+        }
+        if let Some(w) = self.ensemble_weight {
+            if !(0.0..=1.0).contains(&w) {
+                return Err(anyhow!("ensemble_weight 必须在 0 到 1 之间: {}", w));
+            }
+        }
+        if let Some(ref scales) = self.scales {
+            if scales.is_empty() || scales.iter().any(|s| !(s.is_finite() && *s > 0.0)) {
+                return Err(anyhow!(
+                    "scales 不能为空，并且每一项必须大于0: {:?}",
+                    scales
+                ));
+            }
+        }
+        for (name, side) in [("width", self.width), ("height", self.height)] {
+            if let Some(side) = side {
+                if !(1..=Self::MAX_SIDE).contains(&side) {
+                    return Err(anyhow!(
+                        "{} 必须在 1 到 {} 之间: {}",
+                        name,
+                        Self::MAX_SIDE,
+                        side
+                    ));
+                }
+            }
+        }
+        if let Some(n) = self.candidates {
+            if !(1..=Self::MAX_CANDIDATES).contains(&n) {
+                return Err(anyhow!(
+                    "candidates 必须在 1 到 {} 之间: {}",
+                    Self::MAX_CANDIDATES,
+                    n
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 相似度阈值，默认值取决于计算方式，见 [`MatcherKind::default_threshold`]
+    pub fn threshold(&self) -> f64 {
+        match self.threshold {
+            Some(t) if (0.0..=100.0).contains(&t) => t,
+            _ => self.matcher().default_threshold(),
+        }
+    }
+
+    /// 相似度的计算方式，默认 dssim
+    pub fn matcher(&self) -> MatcherKind {
+        self.matcher.unwrap_or_default()
+    }
+
+    /// `ensemble` 模式下 dssim 的权重，默认 0.5
+    pub fn ensemble_weight(&self) -> f64 {
+        match self.ensemble_weight {
+            Some(w) if (0.0..=1.0).contains(&w) => w,
+            _ => 0.5,
+        }
+    }
+
+    /// 领先幅度，默认 2.0
+    pub fn margin(&self) -> f64 {
+        match self.margin {
+            Some(m) if m >= 0.0 => m,
+            _ => 2.0,
+        }
+    }
+
+    /// DSSIM 每个尺度的权重，默认 `[100.0, 100.0]`
+    pub fn scales(&self) -> Vec<f64> {
+        match self.scales {
+            Some(ref s) if !s.is_empty() => s.clone(),
+            _ => vec![100.0, 100.0],
+        }
+    }
+
+    /// 缩放后的尺寸(宽, 高)，默认 120x200
+    pub fn size(&self) -> (u32, u32) {
+        let clamp = |side: Option<u32>, default| match side {
+            Some(s) if (1..=Self::MAX_SIDE).contains(&s) => s,
+            _ => default,
+        };
+        (clamp(self.width, 120), clamp(self.height, 200))
+    }
+
+    /// 缩放时使用的滤波器，默认 nearest
+    pub fn filter(&self) -> ResizeFilter {
+        self.filter.unwrap_or_default()
+    }
+
+    /// 是否保持宽高比，默认不保持
+    pub fn keep_aspect(&self) -> bool {
+        self.keep_aspect.unwrap_or(false)
+    }
+
+    /// 每个选项最多比较几张候选海报，默认 3
+    pub fn candidates(&self) -> usize {
+        match self.candidates {
+            Some(n) if (1..=Self::MAX_CANDIDATES).contains(&n) => n,
+            _ => 3,
+        }
+    }
+}
+
+impl Display for CaptchaConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (width, height) = self.size();
+        write!(
+            f,
+            "Captcha[threshold: {} margin: {} matcher: {} size: {}x{} filter: {} candidates: {}]",
+            self.threshold(),
+            self.margin(),
+            self.matcher(),
+            width,
+            height,
+            self.filter(),
+            self.candidates()
+        )
+    }
+}
+
+/// 海报来源的配置
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PosterSourceConfig {
+    /// 豆瓣
+    Douban,
+    /// 本地文件夹，海报以选项的名字命名
+    Local { dir: String },
+    /// 通用的 JSON 接口
+    Json {
+        url: String,
+        /// 查询参数名，默认 `q`
+        query: Option<String>,
+        /// 图片链接的字段名，默认 `img`
+        img_field: Option<String>,
+        /// 标题的字段名，默认 `title`
+        title_field: Option<String>,
+    },
+}
+
+/// 海报缓存设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheConfig {
+    enable: Option<bool>,
+    /// 过期时间(天)
+    ttl: Option<u64>,
+    /// 图片总大小上限(MB)
+    max_size: Option<u64>,
+}
+
+impl CacheConfig {
+    /// 是否开启，默认开启
+    pub fn enable(&self) -> bool {
+        self.enable.unwrap_or(true)
+    }
+
+    /// 过期时间(天)，默认 30
+    pub fn ttl(&self) -> u64 {
+        self.ttl.unwrap_or(30)
+    }
+
+    /// 图片总大小上限(MB)，默认 64
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(64)
+    }
+}
+
+/// 重试间隔设置
+///
+/// 第 n 次重试前等待 `base * multiplier^(n-1)` 秒，最多 `max` 秒，
+/// 再随机减少最多 `jitter` 的比例，避免多个用户同时重试；用户的设置逐项覆盖全局设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackoffConfig {
+    /// 第一次重试前等待的时间(秒)
+    base: Option<f64>,
+    multiplier: Option<f64>,
+    /// 最长的等待时间(秒)
+    max: Option<f64>,
+    /// 随机减少的最大比例
+    jitter: Option<f64>,
+}
+
+impl BackoffConfig {
+    /// 等待时间的上限(秒)，一天
+    const MAX_SECS: f64 = 86400.0;
+
+    /// 使用 `other` 填充没有设置的项
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            base: self.base.or(other.base),
+            multiplier: self.multiplier.or(other.multiplier),
+            max: self.max.or(other.max),
+            jitter: self.jitter.or(other.jitter),
+        }
+    }
+
+    /// 第一次重试前等待的时间(秒)，默认 5
+    pub fn base(&self) -> f64 {
+        self.base.unwrap_or(5.0)
+    }
+
+    /// 每次重试等待时间的倍数，默认 2
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier.unwrap_or(2.0)
+    }
+
+    /// 最长的等待时间(秒)，默认 120
+    pub fn max(&self) -> f64 {
+        self.max.unwrap_or(120.0)
+    }
+
+    /// 随机减少的最大比例，默认 0.5
+    pub fn jitter(&self) -> f64 {
+        self.jitter.unwrap_or(0.5)
+    }
+
+    /// 第 `retry` 次重试前等待的时间，`random` 是 `[0, 1)` 中的随机数
+    pub fn delay(&self, retry: u32, random: f64) -> Duration {
+        let exp = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = (self.base() * self.multiplier().powi(exp)).min(self.max());
+        Duration::from_secs_f64(secs * (1.0 - self.jitter() * random))
+    }
+
+    /// 最长的等待时间
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.max())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, v) in [("base", self.base()), ("max", self.max())] {
+            if !(0.0..=Self::MAX_SECS).contains(&v) {
+                return Err(anyhow!(
+                    "{} 必须在 0 到 {} 之间: {}",
+                    name,
+                    Self::MAX_SECS,
+                    v
+                ));
+            }
+        }
+        if !self.multiplier().is_finite() || self.multiplier() < 1.0 {
+            return Err(anyhow!("multiplier 不能小于 1: {}", self.multiplier()));
+        }
+        if !(0.0..=1.0).contains(&self.jitter()) {
+            return Err(anyhow!("jitter 必须在 0 到 1 之间: {}", self.jitter()));
+        }
+        Ok(())
+    }
+}
+
+/// 海报来源的请求频率限制
+///
+/// 所有用户共用，每个请求(搜索及下载海报)消耗一次
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    /// 每分钟的请求数，0 表示不限制
+    per_minute: Option<u32>,
+    /// 连续请求的最大数量
+    burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    /// 每分钟的请求数，默认 60
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute.unwrap_or(60)
+    }
+
+    /// 连续请求的最大数量，默认 20
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(20)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.burst() == 0 {
+            return Err(anyhow!("burst 必须大于 0"));
+        }
+        Ok(())
+    }
+}
+
+/// 样本存档设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ArchiveConfig {
+    enable: Option<bool>,
+}
+
+impl ArchiveConfig {
+    /// 是否保存每次验证码的尝试，默认关闭
+    pub fn enable(&self) -> bool {
+        self.enable.unwrap_or(false)
+    }
+}
+
+/// 补签设置
+///
+/// 补签需要消耗魔力值，所以默认关闭
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MakeUpConfig {
+    enable: Option<bool>,
+    /// 每个用户每次运行最多花费的魔力值
+    budget: Option<f64>,
+}
+
+impl MakeUpConfig {
+    /// 是否补签，默认关闭
+    pub fn enable(&self) -> bool {
+        self.enable.unwrap_or(false)
+    }
+
+    /// 每个用户每次运行最多花费的魔力值，默认 0
+    pub fn budget(&self) -> f64 {
+        self.budget.unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(b) = self.budget {
+            if !b.is_finite() || b < 0.0 {
+                return Err(anyhow!("budget 不能小于 0: {}", b));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for MakeUpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MakeUp[enable: {} budget: {}]", self.enable(), self.budget())
+    }
+}
+
+/// 代理设置
+///
+/// 用户的设置逐项覆盖全局设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProxyConfig {
+    /// 设置了 `url` 时默认开启，用户可以通过 `enable = false` 不使用全局的代理
+    enable: Option<bool>,
+    /// `http://`、`https://`、`socks5://` 或 `socks5h://`(由代理解析域名)
+    url: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// 不使用代理的域名或 IP，比如 `localhost`、`.tju.edu.cn`、`10.0.0.0/8`
+    no_proxy: Option<Vec<String>>,
+}
+
+impl ProxyConfig {
+    /// 只有链接，比如命令行中指定的
+    pub fn from_url(url: &str) -> Self {
+        Self {
+            url: Some(url.into()),
+            ..Default::default()
+        }
+    }
+
+    /// 使用 `other` 填充没有设置的项
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            enable: self.enable.or(other.enable),
+            url: self.url.clone().or_else(|| other.url.clone()),
+            username: self.username.clone().or_else(|| other.username.clone()),
+            password: self.password.clone().or_else(|| other.password.clone()),
+            no_proxy: self.no_proxy.clone().or_else(|| other.no_proxy.clone()),
         }
     }
+
+    /// 是否使用代理
+    pub fn enable(&self) -> bool {
+        self.enable.unwrap_or(true) && self.url.is_some()
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// 代理认证 `(username, password)`
+    pub fn auth(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref()?;
+        Some((username, self.password.as_deref().unwrap_or_default()))
+    }
+
+    /// 不使用代理的域名或 IP
+    pub fn no_proxy(&self) -> &[String] {
+        self.no_proxy.as_deref().unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        crate::http::proxy(self).map(|_| ())
+    }
+}
+
+impl Display for ProxyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.url {
+            // 不显示认证信息
+            Some(ref url) if self.enable() => write!(f, "Proxy[{}]", url),
+            _ => write!(f, "Proxy[direct]"),
+        }
+    }
+}
+
+/// 连接使用的IP协议
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    /// 不限制
+    #[default]
+    Any,
+    /// 只使用 IPv4
+    Ipv4,
+    /// 只使用 IPv6
+    Ipv6,
+}
+
+/// HTTP客户端设置
+///
+/// 用户的设置逐项覆盖全局设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HttpConfig {
+    /// 连接超时(秒)
+    connect_timeout: Option<f64>,
+    /// 请求的总超时(秒)，从发送请求到读完响应，以前叫 `read_timeout`
+    #[serde(alias = "read_timeout")]
+    timeout: Option<f64>,
+    user_agent: Option<String>,
+    /// 额外的请求头
+    headers: Option<AHashMap<String, String>>,
+    /// 最多跟随的重定向次数
+    max_redirects: Option<usize>,
+    ip_family: Option<IpFamily>,
+    /// 本地绑定的地址
+    bind: Option<IpAddr>,
+}
+
+impl HttpConfig {
+    /// 超时的上限(秒)
+    const MAX_TIMEOUT: f64 = 3600.0;
+
+    /// 使用 `other` 填充没有设置的项
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            timeout: self.timeout.or(other.timeout),
+            user_agent: self.user_agent.clone().or_else(|| other.user_agent.clone()),
+            headers: self.headers.clone().or_else(|| other.headers.clone()),
+            max_redirects: self.max_redirects.or(other.max_redirects),
+            ip_family: self.ip_family.or(other.ip_family),
+            bind: self.bind.or(other.bind),
+        }
+    }
+
+    /// 连接超时，默认 5 秒
+    pub fn connect_timeout(&self) -> Duration {
+        Self::seconds(self.connect_timeout, 5.0)
+    }
+
+    /// 请求的总超时，包括读完响应的时间，默认 5 秒
+    ///
+    /// 不是两次读取之间的超时，下载较大的海报时需要留出足够的时间
+    pub fn timeout(&self) -> Duration {
+        Self::seconds(self.timeout, 5.0)
+    }
+
+    /// 不合理的值使用默认值
+    fn seconds(t: Option<f64>, default: f64) -> Duration {
+        match t {
+            Some(t) if t > 0.0 && t <= Self::MAX_TIMEOUT => Duration::from_secs_f64(t),
+            _ => Duration::from_secs_f64(default),
+        }
+    }
+
+    /// `None` 表示使用默认的 `User-Agent`
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// 额外的请求头
+    pub fn headers(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.headers.iter().flatten()
+    }
+
+    /// 最多跟随的重定向次数，默认 3
+    pub fn max_redirects(&self) -> usize {
+        self.max_redirects.unwrap_or(3)
+    }
+
+    /// 连接使用的IP协议，默认不限制
+    pub fn ip_family(&self) -> IpFamily {
+        self.ip_family.unwrap_or_default()
+    }
+
+    /// 本地绑定的地址，只使用 IPv4 或 IPv6 时默认绑定对应的任意地址
+    pub fn bind(&self) -> Option<IpAddr> {
+        self.bind.or(match self.ip_family() {
+            IpFamily::Any => None,
+            IpFamily::Ipv4 => Some(IpAddr::from([0u8; 4])),
+            IpFamily::Ipv6 => Some(IpAddr::from([0u16; 8])),
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, t) in [
+            ("connect_timeout", self.connect_timeout),
+            ("timeout", self.timeout),
+        ] {
+            if let Some(t) = t {
+                if !(t > 0.0 && t <= Self::MAX_TIMEOUT) {
+                    return Err(anyhow!(
+                        "{} 必须大于 0 并且不超过 {}: {}",
+                        name,
+                        Self::MAX_TIMEOUT,
+                        t
+                    ));
+                }
+            }
+        }
+        if matches!(
+            (self.bind, self.ip_family()),
+            (Some(IpAddr::V4(_)), IpFamily::Ipv6) | (Some(IpAddr::V6(_)), IpFamily::Ipv4)
+        ) {
+            return Err(anyhow!("bind 与 ip_family 不一致"));
+        }
+        crate::http::headers(self).map(|_| ())
+    }
+}
+
+/// 站点的种类
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SiteKind {
+    /// 北洋园PT，需要根据海报回答问题
+    #[default]
+    Tjupt,
+    /// 访问签到页面即可完成签到的 NexusPHP 站点
+    Nexusphp,
+}
+
+impl Display for SiteKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Tjupt => "tjupt",
+            Self::Nexusphp => "nexusphp",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 站点设置
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SiteConfig {
+    /// 站点的名字，用户通过它选择站点
+    name: Option<String>,
+    /// 站点的种类
+    kind: Option<SiteKind>,
+    /// 站点地址，比如镜像域名或者本地的模拟服务器
+    base_url: Option<String>,
+    /// 登陆页面的路径，相对于 `base_url`
+    login: Option<String>,
+    /// post的登陆路径
+    takelogin: Option<String>,
+    /// 签到页面的路径
+    attendance: Option<String>,
+}
+
+impl SiteConfig {
+    /// 站点的名字，默认 `tjupt`
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("tjupt")
+    }
+
+    /// 站点的种类，默认 `tjupt`
+    pub fn kind(&self) -> SiteKind {
+        self.kind.unwrap_or_default()
+    }
+
+    /// 站点地址，默认 `https://tjupt.org/`
+    pub fn base_url(&self) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or(crate::site::DEFAULT_BASE_URL)
+    }
+
+    /// 默认 `login.php`
+    pub fn login(&self) -> &str {
+        self.login.as_deref().unwrap_or("login.php")
+    }
+
+    /// 默认 `takelogin.php`
+    pub fn takelogin(&self) -> &str {
+        self.takelogin.as_deref().unwrap_or("takelogin.php")
+    }
+
+    /// 默认 `attendance.php`
+    pub fn attendance(&self) -> &str {
+        self.attendance.as_deref().unwrap_or("attendance.php")
+    }
+
+    /// 检查能否得到有效的链接
+    pub fn validate(&self) -> Result<()> {
+        crate::site::SiteUrls::from_config(self).map(|_| ())
+    }
+}
+
+impl Display for SiteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Site[{} {} {}]", self.name(), self.kind(), self.base_url())
+    }
 }
 
 /// 全局配置
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GlobalConfig {
     retry: u8,
+    #[serde(default)]
+    backoff: BackoffConfig,
     emailconf: EmailConfig,
+    #[serde(default)]
+    captcha: CaptchaConfig,
+    /// 海报来源，按顺序尝试
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    posters: Vec<PosterSourceConfig>,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    ratelimit: RateLimitConfig,
+    #[serde(default)]
+    archive: ArchiveConfig,
+    #[serde(default)]
+    makeup: MakeUpConfig,
+    #[serde(default)]
+    proxy: ProxyConfig,
+    #[serde(default)]
+    http: HttpConfig,
 }
 
 impl GlobalConfig {
@@ -300,19 +1301,65 @@ impl GlobalConfig {
         self.retry
     }
 
+    /// 重试间隔设置
+    pub fn backoff(&self) -> &BackoffConfig {
+        &self.backoff
+    }
+
     /// 邮件配置
     pub fn emailconf(&self) -> &EmailConfig {
         &self.emailconf
     }
+
+    /// 验证码设置
+    pub fn captcha(&self) -> &CaptchaConfig {
+        &self.captcha
+    }
+
+    /// 海报来源
+    pub fn posters(&self) -> &[PosterSourceConfig] {
+        &self.posters
+    }
+
+    /// 海报缓存设置
+    pub fn cache(&self) -> &CacheConfig {
+        &self.cache
+    }
+
+    /// 海报来源的请求频率限制
+    pub fn ratelimit(&self) -> &RateLimitConfig {
+        &self.ratelimit
+    }
+
+    /// 样本存档设置
+    pub fn archive(&self) -> &ArchiveConfig {
+        &self.archive
+    }
+
+    /// 补签设置
+    pub fn makeup(&self) -> &MakeUpConfig {
+        &self.makeup
+    }
+
+    /// 代理设置
+    pub fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
+    }
+
+    /// HTTP客户端设置
+    pub fn http(&self) -> &HttpConfig {
+        &self.http
+    }
 }
 
 impl Display for GlobalConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "GlobalConf[retry: {} email: {}]",
+            "GlobalConf[retry: {} email: {} {}]",
             self.retry,
-            self.emailconf.user.as_str()
+            self.emailconf.user.as_str(),
+            self.captcha
         )
     }
 }
@@ -321,7 +1368,122 @@ impl Default for GlobalConfig {
     fn default() -> Self {
         Self {
             retry: 1,
+            backoff: BackoffConfig::default(),
             emailconf: EmailConfig::default(),
+            captcha: CaptchaConfig::default(),
+            posters: vec![],
+            cache: CacheConfig::default(),
+            ratelimit: RateLimitConfig::default(),
+            archive: ArchiveConfig::default(),
+            makeup: MakeUpConfig::default(),
+            proxy: ProxyConfig::default(),
+            http: HttpConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    fn config_file(global_captcha: &str, user_captcha: &str) -> ConfigFile {
+        toml::from_str(&format!(
+            r#"
+            [[users]]
+            enable = true
+            id = "a"
+            pwd = "b"
+            [users.captcha]
+            {}
+
+            [global]
+            retry = 1
+            [global.emailconf]
+            user = "x"
+            pwd = "y"
+            [global.captcha]
+            {}
+            "#,
+            user_captcha, global_captcha
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn captcha_merge_test() {
+        let global: CaptchaConfig =
+            toml::from_str("threshold = 90\nmargin = 5\nwidth = 100\nscales = [2.0, 3.0]").unwrap();
+        let user: CaptchaConfig = toml::from_str("threshold = 95\nscales = [1.0]").unwrap();
+
+        let merged = user.merge(&global);
+        assert_eq!(merged.threshold(), 95.0);
+        assert_eq!(merged.margin(), 5.0);
+        assert_eq!(merged.scales(), vec![1.0]);
+        assert_eq!(merged.size(), (100, 200));
+        assert_eq!(merged.candidates(), 3);
+
+        // 命令行覆盖用户的设置
+        let cli = CaptchaConfig::overrides(None, Some(80.0), None, None);
+        assert_eq!(cli.merge(&merged).threshold(), 80.0);
+
+        // 用户的设置逐项覆盖全局设置
+        let file = config_file("threshold = 90\ncandidates = 5", "threshold = 95");
+        let mut u = file.get_users().pop().unwrap();
+        u.update_captcha(file.gloablconfig());
+        assert_eq!(u.captcha().threshold(), 95.0);
+        assert_eq!(u.captcha().candidates(), 5);
+    }
+
+    #[test]
+    fn captcha_validate_test() {
+        let valid: CaptchaConfig = toml::from_str(
+            "threshold = 100\nscales = [0.5, 1.0]\nwidth = 2000\nheight = 1\ncandidates = 10",
+        )
+        .unwrap();
+        assert!(valid.validate().is_ok());
+
+        for (bad, field) in [
+            ("threshold = 101", "threshold"),
+            ("threshold = -1", "threshold"),
+            ("scales = []", "scales"),
+            ("scales = [1.0, 0.0]", "scales"),
+            ("width = 0", "width"),
+            ("width = 2001", "width"),
+            ("candidates = 0", "candidates"),
+            ("candidates = 11", "candidates"),
+        ] {
+            let config: CaptchaConfig = toml::from_str(bad).unwrap();
+            let e = config.validate().unwrap_err().to_string();
+            assert!(e.starts_with(field), "{}: {}", bad, e);
+        }
+
+        // 不合理的值在使用时回到默认值
+        let config: CaptchaConfig = toml::from_str("threshold = 101\nwidth = 0").unwrap();
+        assert_eq!(config.threshold(), 93.0);
+        assert_eq!(config.size(), (120, 200));
+
+        let config: CaptchaConfig = toml::from_str("matcher = \"phash\"").unwrap();
+        assert_eq!(config.threshold(), 80.0);
+        // 合并后按最终的计算方式取默认值
+        let cli = CaptchaConfig::overrides(Some(MatcherKind::Dhash), None, None, None);
+        assert_eq!(cli.merge(&CaptchaConfig::default()).threshold(), 82.0);
+    }
+
+    #[test]
+    fn config_file_captcha_test() {
+        let file = config_file("threshold = 90", "threshold = 95");
+        assert!(file.validate().is_ok());
+
+        let e = config_file("candidates = 11", "").validate().unwrap_err();
+        let e = e.to_string();
+        assert!(
+            e.starts_with("[global.captcha] 设置有误: candidates"),
+            "{}",
+            e
+        );
+
+        let e = config_file("", "scales = []").validate().unwrap_err();
+        let e = e.to_string();
+        assert!(e.starts_with("用户 a 的验证码设置有误: scales"), "{}", e);
+    }
+}
@@ -0,0 +1,262 @@
+//! 导入浏览器的cookie
+//!
+//! 支持的格式:
+//! - Netscape `cookies.txt`
+//! - 请求头中的 `Cookie: a=1; b=2`
+//! - 常见的 JSON 导出格式: 对象数组、`{"cookies": [...]}`、`{"name": "value"}`
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use reqwest_cookie_store::CookieStore;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 会话cookie没有过期时间，保存时按7天计算，与登陆时的 `logout=7days` 一致
+const SESSION_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+/// 从浏览器导出的一条cookie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowserCookie {
+    pub name: String,
+    pub value: String,
+    /// 不指定则属于站点本身
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    /// 过期时间(unix 秒)，`None` 是会话cookie
+    pub expires: Option<u64>,
+    pub secure: bool,
+    pub http_only: bool,
+    /// 只属于 `domain` 本身，不包括子域名
+    pub host_only: bool,
+}
+
+impl BrowserCookie {
+    fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            domain: None,
+            path: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            host_only: false,
+        }
+    }
+
+    /// 是否属于 `host`
+    fn matches(&self, host: &str) -> bool {
+        let Some(ref domain) = self.domain else {
+            return true;
+        };
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        let host = host.to_lowercase();
+        host == domain || (!self.host_only && host.ends_with(&format!(".{}", domain)))
+    }
+
+    /// 转换为 `Set-Cookie` 的格式
+    ///
+    /// 已经过期的返回 `None`
+    fn to_set_cookie(&self, now: u64) -> Option<String> {
+        let max_age = match self.expires {
+            Some(expires) if expires <= now => return None,
+            Some(expires) => expires - now,
+            None => SESSION_MAX_AGE,
+        };
+        let mut s = format!(
+            "{}={}; Path={}; Max-Age={}",
+            self.name,
+            self.value,
+            self.path.as_deref().unwrap_or("/"),
+            max_age
+        );
+        if let Some(ref domain) = self.domain {
+            if !self.host_only {
+                s.push_str(&format!("; Domain={}", domain.trim_start_matches('.')));
+            }
+        }
+        if self.secure {
+            s.push_str("; Secure");
+        }
+        if self.http_only {
+            s.push_str("; HttpOnly");
+        }
+        Some(s)
+    }
+}
+
+/// 自动判断格式并解析
+pub fn parse(content: &str) -> Result<Vec<BrowserCookie>> {
+    let content = content.trim();
+    let cookies = if content.starts_with('[') || content.starts_with('{') {
+        parse_json(&serde_json::from_str(content)?)?
+    } else if content.lines().any(|l| l.split('\t').count() == 7) {
+        parse_netscape(content)
+    } else {
+        parse_header(content)
+    };
+
+    if cookies.is_empty() {
+        return Err(anyhow!("无法解析出任何cookie"));
+    }
+    Ok(cookies)
+}
+
+/// Netscape `cookies.txt`
+///
+/// 每行: `domain include_subdomains path secure expires name value`，以 tab 分隔
+fn parse_netscape(content: &str) -> Vec<BrowserCookie> {
+    content
+        .lines()
+        .filter_map(|line| {
+            // curl 等工具以此标记 HttpOnly
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(l) => (l, true),
+                None => (line, false),
+            };
+            if line.starts_with('#') {
+                return None;
+            }
+            let fields: Vec<&str> = line.trim_end_matches('\r').split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+                return None;
+            };
+            Some(BrowserCookie {
+                domain: Some(domain.into()),
+                path: Some(path.into()),
+                expires: expires.parse().ok().filter(|e| *e > 0),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                http_only,
+                host_only: !subdomains.eq_ignore_ascii_case("TRUE"),
+                ..BrowserCookie::new(name, value)
+            })
+        })
+        .collect()
+}
+
+/// `Cookie: a=1; b=2`，可以省略 `Cookie:`
+fn parse_header(content: &str) -> Vec<BrowserCookie> {
+    let content = content
+        .split_once(':')
+        .filter(|(k, _)| k.trim().eq_ignore_ascii_case("cookie"))
+        .map(|(_, v)| v)
+        .unwrap_or(content);
+    content
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            (!name.is_empty()).then(|| BrowserCookie::new(name, value.trim()))
+        })
+        .collect()
+}
+
+/// 浏览器扩展(EditThisCookie、Cookie-Editor)、Playwright、Puppeteer 等导出的 JSON
+fn parse_json(value: &Value) -> Result<Vec<BrowserCookie>> {
+    match value {
+        Value::Array(items) => Ok(items.iter().filter_map(json_cookie).collect()),
+        Value::Object(map) => match map.get("cookies") {
+            Some(cookies) => parse_json(cookies),
+            None => Ok(map
+                .iter()
+                .filter_map(|(k, v)| Some(BrowserCookie::new(k, v.as_str()?)))
+                .collect()),
+        },
+        _ => Err(anyhow!("无法识别的 JSON 格式")),
+    }
+}
+
+fn json_cookie(item: &Value) -> Option<BrowserCookie> {
+    let str_field = |key: &str| item.get(key).and_then(Value::as_str);
+    let bool_field = |key: &str| item.get(key).and_then(Value::as_bool).unwrap_or_default();
+
+    let session = bool_field("session");
+    let expires = ["expirationDate", "expires", "expiry"]
+        .iter()
+        .find_map(|k| item.get(*k).and_then(Value::as_f64))
+        .filter(|e| !session && *e > 0.0)
+        .map(|e| e as u64);
+
+    Some(BrowserCookie {
+        domain: str_field("domain").map(String::from),
+        path: str_field("path").map(String::from),
+        expires,
+        secure: bool_field("secure"),
+        http_only: bool_field("httpOnly"),
+        host_only: bool_field("hostOnly"),
+        ..BrowserCookie::new(str_field("name")?, str_field("value")?)
+    })
+}
+
+/// 把属于站点的cookie放入 `CookieStore`
+///
+/// 返回 `CookieStore` 以及导入的数量，其他域名的和已经过期的会被跳过
+pub fn to_store(cookies: &[BrowserCookie], site: &Url) -> Result<(CookieStore, usize)> {
+    let Some(host) = site.host_str() else {
+        return Err(anyhow!("站点链接没有域名: {}", site));
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut store = CookieStore::default();
+    let mut imported = 0;
+    for cookie in cookies.iter() {
+        if !cookie.matches(host) {
+            continue;
+        }
+        let Some(s) = cookie.to_set_cookie(now) else {
+            log::debug!("跳过已经过期的cookie: {}", cookie.name);
+            continue;
+        };
+        match store.parse(&s, site) {
+            Ok(_) => imported += 1,
+            Err(e) => log::debug!("无法导入cookie: {}, Err: {}", cookie.name, e),
+        }
+    }
+
+    if imported == 0 {
+        return Err(anyhow!("没有属于 {} 的有效cookie", host));
+    }
+    Ok((store, imported))
+}
+
+#[cfg(test)]
+mod cookies_test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let header = parse("Cookie: c_secure_uid=MTIz; c_secure_pass=abc").unwrap();
+        assert_eq!(header.len(), 2);
+        assert_eq!(header[1], BrowserCookie::new("c_secure_pass", "abc"));
+
+        let netscape = parse(
+            "# Netscape HTTP Cookie File\n\
+            .tjupt.org\tTRUE\t/\tTRUE\t4102444800\tc_secure_uid\tMTIz\n\
+            #HttpOnly_tjupt.org\tFALSE\t/\tFALSE\t0\tsid\tx\n\
+            example.com\tFALSE\t/\tFALSE\t0\tother\ty",
+        )
+        .unwrap();
+        assert_eq!(netscape.len(), 3);
+        assert_eq!(netscape[0].expires, Some(4102444800));
+        assert!(netscape[1].http_only && netscape[1].host_only);
+
+        let json = parse(
+            r#"[{"domain": ".tjupt.org", "name": "a", "value": "1", "expirationDate": 4102444800.5},
+                {"domain": "tjupt.org", "name": "b", "value": "2", "session": true}]"#,
+        )
+        .unwrap();
+        assert_eq!(json[0].expires, Some(4102444800));
+        assert_eq!(json[1].expires, None);
+        assert_eq!(parse(r#"{"cookies": [{"name": "a", "value": "1"}]}"#).unwrap().len(), 1);
+        assert_eq!(parse(r#"{"a": "1", "b": "2"}"#).unwrap().len(), 2);
+
+        // 其他域名的被跳过
+        let site = Url::parse("https://tjupt.org/").unwrap();
+        let (store, imported) = to_store(&netscape, &site).unwrap();
+        assert_eq!(imported, 2);
+        assert_eq!(store.matches(&site).len(), 2);
+    }
+}
@@ -0,0 +1,492 @@
+//! 站点及海报来源的客户端共用的HTTP设置
+//!
+//! 设置相同的用户共用一个客户端(连接池)，cookie 由 [`Session`] 在每次请求时单独处理
+
+use crate::command::HEADER;
+use crate::config::{BackoffConfig, HttpConfig, ProxyConfig, RateLimitConfig};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use reqwest::{
+    cookie::CookieStore,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION,
+        REFERER, RETRY_AFTER, SET_COOKIE, USER_AGENT,
+    },
+    redirect, Client, ClientBuilder, IntoUrl, Method, NoProxy, Proxy, Request, RequestBuilder,
+    Response, StatusCode, Url,
+};
+use reqwest_cookie_store::CookieStoreMutex;
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::time::Instant;
+
+/// 支持的代理协议
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+lazy_static! {
+    /// 站点的客户端，按设置区分
+    static ref CLIENTS: Mutex<Vec<(HttpConfig, ProxyConfig, Client)>> = Mutex::new(vec![]);
+}
+
+/// 用户访问站点的会话
+///
+/// 客户端(连接池)由设置相同的用户共用，cookie 每个用户单独保存；
+/// 客户端不跟随重定向，由会话跟随，这样每一跳都使用并更新用户自己的 cookie
+#[derive(Debug, Clone)]
+pub struct Session {
+    client: Client,
+    cookie: Arc<CookieStoreMutex>,
+    max_redirects: usize,
+}
+
+impl Session {
+    pub fn new(
+        http: &HttpConfig,
+        proxy: &ProxyConfig,
+        cookie: Arc<CookieStoreMutex>,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: shared_client(http, proxy)?,
+            cookie,
+            max_redirects: http.max_redirects(),
+        })
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> SessionRequest<'_> {
+        SessionRequest {
+            session: self,
+            builder: self.client.get(url),
+        }
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> SessionRequest<'_> {
+        SessionRequest {
+            session: self,
+            builder: self.client.post(url),
+        }
+    }
+
+    /// 发送请求，跟随重定向
+    ///
+    /// 超过重定向次数时返回错误，`max_redirects` 为 0 时直接返回重定向的响应
+    async fn execute(&self, mut req: Request) -> Result<Response> {
+        let mut redirects = 0;
+        loop {
+            let url = req.url().clone();
+            // 307/308 需要原样重新发送
+            let resend = req.try_clone();
+            req.headers_mut().remove(COOKIE);
+            if let Some(cookie) = self.cookie.cookies(&url) {
+                req.headers_mut().insert(COOKIE, cookie);
+            }
+            let res = self.client.execute(req).await?;
+            self.cookie
+                .set_cookies(&mut res.headers().get_all(SET_COOKIE).iter(), &url);
+
+            if let Some(throttled) = Throttled::check(&res) {
+                return Err(throttled.into());
+            }
+            let Some(next) = redirect_target(&res) else {
+                return Ok(res);
+            };
+            if self.max_redirects == 0 {
+                return Ok(res);
+            }
+            if redirects == self.max_redirects {
+                return Err(anyhow!("重定向次数过多: {}", url));
+            }
+            redirects += 1;
+
+            req = match (res.status(), resend) {
+                (StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT, Some(mut r)) => {
+                    *r.url_mut() = next;
+                    r
+                }
+                (_, resend) => {
+                    // 其他重定向改为不带请求体的 GET
+                    let mut r = Request::new(Method::GET, next);
+                    if let Some(prev) = resend {
+                        *r.headers_mut() = prev.headers().clone();
+                    }
+                    r.headers_mut().remove(CONTENT_TYPE);
+                    r.headers_mut().remove(CONTENT_LENGTH);
+                    r
+                }
+            };
+            // 与 reqwest 相同，从 https 跳转到 http 时不发送 Referer
+            req.headers_mut().remove(REFERER);
+            if !(url.scheme() == "https" && req.url().scheme() == "http") {
+                if let Ok(referer) = HeaderValue::from_str(url.as_str()) {
+                    req.headers_mut().insert(REFERER, referer);
+                }
+            }
+        }
+    }
+}
+
+/// 会话中的请求
+pub struct SessionRequest<'a> {
+    session: &'a Session,
+    builder: RequestBuilder,
+}
+
+impl SessionRequest<'_> {
+    pub fn form<T: Serialize + ?Sized>(mut self, form: &T) -> Self {
+        self.builder = self.builder.form(form);
+        self
+    }
+
+    pub fn query<T: Serialize + ?Sized>(mut self, query: &T) -> Self {
+        self.builder = self.builder.query(query);
+        self
+    }
+
+    pub async fn send(self) -> Result<Response> {
+        self.session.execute(self.builder.build()?).await
+    }
+}
+
+/// 服务器要求稍后再试，HTTP 429 或 503
+#[derive(Debug, Clone)]
+pub struct Throttled {
+    pub status: StatusCode,
+    /// `Retry-After` 要求等待的时间
+    pub retry_after: Option<Duration>,
+}
+
+impl Throttled {
+    /// 响应是 429 或 503 时返回 `Some`
+    pub fn check(res: &Response) -> Option<Self> {
+        match res.status() {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => Some(Self {
+                status: res.status(),
+                retry_after: res.headers().get(RETRY_AFTER).and_then(retry_after),
+            }),
+            _ => None,
+        }
+    }
+
+    /// 在 `anyhow::Error` 的错误链中查找
+    pub fn find(e: &anyhow::Error) -> Option<&Self> {
+        e.chain().find_map(|e| e.downcast_ref::<Self>())
+    }
+}
+
+impl Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "服务器要求稍后再试({})", self.status)?;
+        match self.retry_after {
+            Some(d) => write!(f, "，等待 {} 秒", d.as_secs()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// 解析 `Retry-After`，可以是秒数或者 HTTP 日期
+fn retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// 重定向的目标，不是重定向时返回 `None`
+fn redirect_target(res: &Response) -> Option<Url> {
+    match res.status() {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => {}
+        _ => return None,
+    }
+    let location = res.headers().get(LOCATION)?.to_str().ok()?;
+    res.url().join(location).ok()
+}
+
+/// 请求频率限制
+///
+/// 最多连续发送 `burst` 个请求，之后每分钟最多 `per_minute` 个，等待的请求按先后顺序发送
+///
+/// 服务器要求稍后再试时，之后的所有请求都推迟到 `Retry-After` 之后，
+/// 需要等待的时间超过 `max_wait` 时不再等待，直接返回 [`Throttled`]
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// 两个请求的间隔，`None` 表示不限制
+    interval: Option<Duration>,
+    /// 可以提前发送的时间
+    tolerance: Duration,
+    /// 下一个请求按间隔计算的发送时间
+    next: Mutex<Instant>,
+    /// `Retry-After` 要求的时间之前不发送请求
+    blocked: Mutex<Option<(Instant, StatusCode)>>,
+    /// 最长的等待时间
+    max_wait: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, max_wait: Duration) -> Self {
+        let interval =
+            (config.per_minute() > 0).then(|| Duration::from_secs(60) / config.per_minute());
+        Self {
+            interval,
+            tolerance: interval.unwrap_or_default() * config.burst().saturating_sub(1),
+            next: Mutex::new(Instant::now()),
+            blocked: Mutex::new(None),
+            max_wait,
+        }
+    }
+
+    /// 等待后发送请求
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        self.acquire().await?;
+        let res = req.send().await?;
+        let Some(throttled) = Throttled::check(&res) else {
+            return Ok(res);
+        };
+        if let Some(wait) = throttled.retry_after {
+            let mut blocked = self.blocked.lock().map_err(|_| anyhow!("无法获取锁"))?;
+            let until = Instant::now() + wait;
+            if blocked.is_none_or(|(t, _)| t < until) {
+                *blocked = Some((until, throttled.status));
+            }
+        }
+        Err(throttled.into())
+    }
+
+    /// 等待到可以发送请求
+    async fn acquire(&self) -> Result<()> {
+        let blocked = *self.blocked.lock().map_err(|_| anyhow!("无法获取锁"))?;
+        if let Some((until, status)) = blocked {
+            let wait = until.saturating_duration_since(Instant::now());
+            if wait > self.max_wait {
+                let retry_after = Some(wait);
+                return Err(Throttled { status, retry_after }.into());
+            }
+            if !wait.is_zero() {
+                log::debug!("服务器要求稍后再试，等待 {:.1} 秒", wait.as_secs_f64());
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let wait = {
+            let mut next = self.next.lock().map_err(|_| anyhow!("无法获取锁"))?;
+            let now = Instant::now();
+            let due = (*next).max(now);
+            match self.interval {
+                None => Duration::ZERO,
+                Some(interval) => {
+                    *next = due + interval;
+                    due.checked_sub(self.tolerance)
+                        .map(|t| t.saturating_duration_since(now))
+                        .unwrap_or_default()
+                }
+            }
+        };
+        if !wait.is_zero() {
+            log::debug!("请求过于频繁，等待 {:.1} 秒", wait.as_secs_f64());
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(
+            &RateLimitConfig::default(),
+            BackoffConfig::default().max_delay(),
+        )
+    }
+}
+
+/// 设置相同时返回同一个客户端，不跟随重定向也不保存 cookie
+fn shared_client(http: &HttpConfig, proxy: &ProxyConfig) -> Result<Client> {
+    let mut clients = CLIENTS.lock().map_err(|_| anyhow!("无法获取锁"))?;
+    if let Some((_, _, client)) = clients.iter().find(|(h, p, _)| h == http && p == proxy) {
+        return Ok(client.clone());
+    }
+    let client = builder(http, proxy)?
+        .redirect(redirect::Policy::none())
+        .connection_verbose(false)
+        .build()?;
+    clients.push((http.clone(), proxy.clone(), client.clone()));
+    Ok(client)
+}
+
+/// 根据设置创建客户端，之后可以继续设置cookie等
+pub fn builder(http: &HttpConfig, proxy: &ProxyConfig) -> Result<ClientBuilder> {
+    let redirect = match http.max_redirects() {
+        0 => redirect::Policy::none(),
+        n => redirect::Policy::limited(n),
+    };
+    let mut builder = ClientBuilder::new()
+        .connect_timeout(http.connect_timeout())
+        // reqwest 没有单独的读取超时，这是整个请求的超时
+        .timeout(http.timeout())
+        .default_headers(headers(http)?)
+        .redirect(redirect)
+        .local_address(http.bind());
+    if let Some(proxy) = self::proxy(proxy)? {
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder)
+}
+
+/// 默认的请求头，加上设置中的 `User-Agent` 及额外的请求头
+pub fn headers(http: &HttpConfig) -> Result<HeaderMap> {
+    let mut headers = HEADER.clone();
+    if let Some(ua) = http.user_agent() {
+        let ua = HeaderValue::from_str(ua).map_err(|e| anyhow!("无效的 user_agent: {}", e))?;
+        headers.insert(USER_AGENT, ua);
+    }
+    for (name, value) in http.headers() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| anyhow!("无效的请求头 {}: {}", name, e))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|e| anyhow!("无效的请求头 {}: {}", name, e))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// 根据设置创建代理，不使用代理时返回 `None`
+///
+/// 认证信息写入链接中，这样 socks5 代理也可以认证
+pub fn proxy(config: &ProxyConfig) -> Result<Option<Proxy>> {
+    let Some(url) = config.url().filter(|_| config.enable()) else {
+        return Ok(None);
+    };
+    let mut url = Url::parse(url).map_err(|e| anyhow!("无效的代理链接 {}: {}", url, e))?;
+    if !PROXY_SCHEMES.contains(&url.scheme()) {
+        return Err(anyhow!(
+            "不支持的代理协议: {}，可以是 {}",
+            url.scheme(),
+            PROXY_SCHEMES.join(", ")
+        ));
+    }
+    if let Some((username, password)) = config.auth() {
+        url.set_username(username)
+            .and_then(|_| url.set_password(Some(password)))
+            .map_err(|_| anyhow!("无法设置代理的认证信息: {}", url))?;
+    }
+
+    let no_proxy = NoProxy::from_string(&config.no_proxy().join(","));
+    Ok(Some(Proxy::all(url)?.no_proxy(no_proxy)))
+}
+
+#[cfg(test)]
+mod http_test {
+    use super::*;
+
+    #[test]
+    fn proxy_test() {
+        let config: ProxyConfig = toml::from_str(
+            r#"
+            url = "socks5h://127.0.0.1:1080"
+            username = "user"
+            password = "p@ss"
+            no_proxy = ["localhost", ".tju.edu.cn"]
+            "#,
+        )
+        .unwrap();
+        assert!(proxy(&config).unwrap().is_some());
+
+        let disabled: ProxyConfig = toml::from_str("enable = false").unwrap();
+        assert!(proxy(&disabled.merge(&config)).unwrap().is_none());
+        assert!(proxy(&ProxyConfig::default()).unwrap().is_none());
+        assert!(proxy(&ProxyConfig::from_url("ftp://127.0.0.1")).is_err());
+    }
+
+    #[test]
+    fn http_test() {
+        let global: HttpConfig = toml::from_str(
+            r#"
+            user_agent = "tjuptatt"
+            ip_family = "ipv6"
+            headers = { "Accept-Language" = "zh-CN" }
+            "#,
+        )
+        .unwrap();
+        let user: HttpConfig = toml::from_str("timeout = 30").unwrap();
+        let http = user.merge(&global);
+        assert_eq!(http.timeout().as_secs(), 30);
+        assert_eq!(http.max_redirects(), 3);
+        assert_eq!(http.bind(), Some("::".parse().unwrap()));
+
+        let headers = headers(&http).unwrap();
+        assert_eq!(headers[USER_AGENT], "tjuptatt");
+        assert_eq!(headers["accept-language"], "zh-CN");
+        assert!(builder(&http, &ProxyConfig::default()).is_ok());
+
+        let bad: HttpConfig = toml::from_str(r#"bind = "127.0.0.1""#).unwrap();
+        assert!(bad.merge(&global).validate().is_err());
+
+        // 以前的名字仍然可以使用
+        let old: HttpConfig = toml::from_str("read_timeout = 30").unwrap();
+        assert_eq!(old.timeout().as_secs(), 30);
+        // 太大的值不会导致 panic
+        let huge: HttpConfig = toml::from_str("timeout = 1e300\nconnect_timeout = 0").unwrap();
+        assert!(huge.validate().is_err());
+        assert_eq!(huge.timeout().as_secs(), 5);
+        assert_eq!(huge.connect_timeout().as_secs(), 5);
+    }
+
+    #[test]
+    fn shared_client_test() {
+        let http: HttpConfig = toml::from_str("timeout = 7").unwrap();
+        let proxy = ProxyConfig::default();
+        Session::new(&http, &proxy, Arc::default()).unwrap();
+        Session::new(&http.merge(&HttpConfig::default()), &proxy, Arc::default()).unwrap();
+
+        let clients = CLIENTS.lock().unwrap();
+        assert_eq!(clients.iter().filter(|(h, p, _)| h == &http && p == &proxy).count(), 1);
+    }
+
+    #[test]
+    fn retry_test() {
+        let header = |s: &str| HeaderValue::from_str(s).unwrap();
+        assert_eq!(retry_after(&header("120")), Some(Duration::from_secs(120)));
+        let past = header("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(retry_after(&past), Some(Duration::ZERO));
+        assert_eq!(retry_after(&header("soon")), None);
+
+        let backoff: BackoffConfig = toml::from_str("base = 2\nmax = 10").unwrap();
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff.delay(3, 0.0), Duration::from_secs(8));
+        assert_eq!(backoff.delay(4, 0.0), Duration::from_secs(10));
+        // 默认随机减少最多一半
+        assert_eq!(backoff.delay(4, 0.5), Duration::from_secs_f64(7.5));
+        for bad in ["base = -1", "max = 1e300", "base = 100000"] {
+            let backoff: BackoffConfig = toml::from_str(bad).unwrap();
+            assert!(backoff.validate().is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_test() {
+        let config: RateLimitConfig = toml::from_str("per_minute = 600\nburst = 2").unwrap();
+        let limiter = RateLimiter::new(&config, Duration::from_secs(1));
+        let start = Instant::now();
+        limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // 超过 burst 后每 100 毫秒一个
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // `Retry-After` 超过最长的等待时间时不再等待
+        let until = Instant::now() + Duration::from_secs(60);
+        *limiter.blocked.lock().unwrap() = Some((until, StatusCode::TOO_MANY_REQUESTS));
+        let e = limiter.acquire().await.unwrap_err();
+        assert!(Throttled::find(&e).unwrap().retry_after.unwrap() > Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,482 @@
+//! 登陆
+//!
+//! - 登陆失败的原因，根据 takelogin 返回的页面判断，有些错误重试也没有用，
+//!   反而可能导致账号被锁定
+//! - 登陆时的图片验证码及两步验证码(TOTP)，图片验证码需要在终端中手动输入
+
+use crate::{archive::sanitize, command::DIRS, config::UserConfig, http::Session};
+use bytes::Bytes;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+use sha1::Sha1;
+use std::{
+    fmt::Display,
+    io::{IsTerminal, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// 图片验证码输入框的名字
+const CAPTCHA_FIELDS: [&str; 3] = ["imagestring", "captcha", "verify_code"];
+/// 两步验证码输入框的名字
+const TOTP_FIELDS: [&str; 4] = ["two_step_code", "2fa_code", "otp", "totp"];
+/// TOTP 的时间步长(秒)
+const TOTP_STEP: u64 = 30;
+
+lazy_static! {
+    /// NexusPHP 错误页面中的说明
+    static ref MESSAGE: Selector = Selector::parse("td.text").unwrap();
+    static ref FORM: Selector = Selector::parse("form").unwrap();
+    static ref INPUT: Selector = Selector::parse("input[name]").unwrap();
+    static ref IMG: Selector = Selector::parse("img[src]").unwrap();
+    /// 同时只有一个用户在终端中输入验证码
+    static ref PROMPT: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// 登陆失败的种类
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginFailure {
+    /// 用户名或密码错误
+    Credentials,
+    /// 账号被禁用或者封禁
+    Disabled,
+    /// 失败次数过多，被暂时锁定
+    Locked,
+    /// 需要登陆验证码
+    Captcha,
+    /// 需要两步验证码
+    TwoFactor,
+    /// 网络错误
+    Transport,
+    /// 无法判断，比如登陆后仍然被重定向到登陆页面
+    Unknown,
+}
+
+impl LoginFailure {
+    /// 按顺序匹配，锁定及封禁的页面上常常也有 `密码` 字样
+    const MARKERS: [(Self, &'static [&'static str]); 5] = [
+        (
+            Self::Locked,
+            &["次数过多", "尝试过多", "已被锁定", "暂时禁止", "too many"],
+        ),
+        (
+            Self::Disabled,
+            &["禁用", "封禁", "已被停用", "banned", "disabled"],
+        ),
+        (Self::TwoFactor, &["两步验证", "二次验证", "two-step", "2fa"]),
+        (Self::Captcha, &["验证码", "captcha"]),
+        (
+            Self::Credentials,
+            &["密码错误", "密码不正确", "用户名不存在", "用户名或密码", "incorrect"],
+        ),
+    ];
+
+    /// 重试能否解决
+    pub fn retryable(&self) -> bool {
+        matches!(self, Self::Transport | Self::Unknown)
+    }
+}
+
+impl Display for LoginFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Credentials => "用户名或密码错误",
+            Self::Disabled => "账号被禁用",
+            Self::Locked => "失败次数过多，账号被锁定",
+            Self::Captcha => "需要验证码",
+            Self::TwoFactor => "需要两步验证码",
+            Self::Transport => "网络错误",
+            Self::Unknown => "未知原因",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 登陆失败
+#[derive(Debug)]
+pub struct LoginError {
+    pub failure: LoginFailure,
+    /// 页面上的说明或者具体的错误
+    pub message: String,
+    /// 发送请求时的错误，比如服务器要求稍后再试
+    source: Option<anyhow::Error>,
+}
+
+impl LoginError {
+    pub fn new(failure: LoginFailure, message: impl Into<String>) -> Self {
+        Self {
+            failure,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// 根据 takelogin 返回的页面判断原因
+    ///
+    /// 优先只看页面上的错误说明，重新显示的登陆表单中也有 `验证码` 等字样；
+    /// 没有说明时才查找整个页面，不包括脚本及样式。
+    /// 没有找到错误信息时返回 `None`
+    pub fn classify(html: &str) -> Option<Self> {
+        let doc = Html::parse_document(html);
+        let message = doc
+            .select(&MESSAGE)
+            .map(|e| e.text().collect::<String>().trim().to_string())
+            .find(|m| !m.is_empty());
+
+        let failure = match message {
+            Some(ref m) => Self::find(&m.to_lowercase())?,
+            None => Self::find(&visible_text(&doc).to_lowercase())?,
+        };
+        Some(Self::new(failure, message.unwrap_or_default()))
+    }
+
+    fn find(text: &str) -> Option<LoginFailure> {
+        LoginFailure::MARKERS
+            .iter()
+            .find(|(_, markers)| markers.iter().any(|m| text.contains(m)))
+            .map(|(failure, _)| *failure)
+    }
+}
+
+/// 页面上的文字，不包括脚本及样式
+fn visible_text(doc: &Html) -> String {
+    doc.root_element()
+        .descendants()
+        .filter(|node| {
+            node.ancestors().all(|a| {
+                a.value()
+                    .as_element()
+                    .map(|e| !matches!(e.name(), "script" | "style" | "noscript" | "template"))
+                    .unwrap_or(true)
+            })
+        })
+        .filter_map(|node| node.value().as_text().map(|t| &**t))
+        .collect()
+}
+
+impl From<reqwest::Error> for LoginError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::new(LoginFailure::Transport, e.to_string())
+    }
+}
+
+/// 发送请求时的错误，比如重定向次数过多
+impl From<anyhow::Error> for LoginError {
+    fn from(e: anyhow::Error) -> Self {
+        Self {
+            source: Some(e),
+            ..Self::new(LoginFailure::Transport, "")
+        }
+    }
+}
+
+impl Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.source {
+            Some(ref e) => write!(f, "登陆失败: {}: {}", self.failure, e),
+            None if self.message.is_empty() => write!(f, "登陆失败: {}", self.failure),
+            None => write!(f, "登陆失败: {}: {}", self.failure, self.message),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|e| e.as_ref() as _)
+    }
+}
+
+/// 登陆表单中需要额外填写的内容
+#[derive(Debug, Default)]
+pub struct LoginForm {
+    /// 表单提交的链接
+    pub action: Option<String>,
+    /// 隐藏的字段，比如验证码的 `imagehash`
+    pub hidden: Vec<(String, String)>,
+    /// 图片验证码 `(输入框的名字, 图片链接)`
+    pub captcha: Option<(String, String)>,
+    /// 两步验证码输入框的名字
+    pub totp: Option<String>,
+    /// 表单中有密码输入框，即登陆表单而不是单独的两步验证页面
+    pub password: bool,
+}
+
+impl LoginForm {
+    /// 解析页面中的登陆表单，没有表单时返回空的
+    pub fn parse(html: &str) -> Self {
+        let doc = Html::parse_document(html);
+        let field = |form: &ElementRef, names: &[&str]| {
+            form.select(&INPUT)
+                .filter_map(|e| e.value().attr("name"))
+                .find(|n| names.contains(n))
+                .map(String::from)
+        };
+
+        let has_password = |form: &ElementRef| {
+            form.select(&INPUT)
+                .any(|e| e.value().attr("type") == Some("password"))
+        };
+        let Some(form) = doc
+            .select(&FORM)
+            .find(|form| has_password(form) || field(form, &TOTP_FIELDS).is_some())
+        else {
+            return Self::default();
+        };
+
+        let captcha = field(&form, &CAPTCHA_FIELDS).and_then(|name| {
+            // 图片可能不在表单里
+            let src = |img: ElementRef<'_>| img.value().attr("src").map(String::from);
+            let is_captcha = |s: &String| s.contains("captcha") || s.contains("image.php");
+            let img = form
+                .select(&IMG)
+                .filter_map(src)
+                .find(is_captcha)
+                .or_else(|| doc.select(&IMG).filter_map(src).find(is_captcha))
+                .or_else(|| form.select(&IMG).next().and_then(src))?;
+            Some((name, img))
+        });
+        let hidden = form
+            .select(&INPUT)
+            .filter(|e| e.value().attr("type") == Some("hidden"))
+            .filter_map(|e| {
+                let name = e.value().attr("name")?;
+                Some((name.into(), e.value().attr("value").unwrap_or_default().into()))
+            })
+            .collect();
+
+        Self {
+            action: form.value().attr("action").map(String::from),
+            hidden,
+            captcha,
+            totp: field(&form, &TOTP_FIELDS),
+            password: has_password(&form),
+        }
+    }
+
+    /// 填写验证码
+    ///
+    /// 两步验证码根据用户的 `totp` 生成，登陆表单中的两步验证码是可选的
+    /// (比如 NexusPHP 1.7)，没有设置 `totp` 时不填写，由站点判断是否需要；
+    /// 图片验证码在终端中询问；`page` 是表单所在页面的链接
+    pub async fn answer(
+        &self,
+        client: &Session,
+        user: &UserConfig,
+        page: &Url,
+    ) -> Result<Vec<(String, String)>, LoginError> {
+        let mut fields = vec![];
+        match (self.totp.as_ref(), user.totp()) {
+            (Some(name), Some(secret)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                let code =
+                    totp(secret, now).map_err(|e| LoginError::new(LoginFailure::TwoFactor, e))?;
+                fields.push((name.clone(), code));
+            }
+            (Some(_), None) if !self.password => {
+                return Err(LoginError::new(
+                    LoginFailure::TwoFactor,
+                    "没有设置用户的 totp",
+                ));
+            }
+            _ => {}
+        }
+        if let Some((ref name, ref img)) = self.captcha {
+            let url = page
+                .join(img)
+                .map_err(|e| LoginError::new(LoginFailure::Captcha, e.to_string()))?;
+            let bytes = client.get(url).send().await?.bytes().await?;
+            fields.push((name.clone(), prompt_captcha(user.id(), bytes).await?));
+        }
+        Ok(fields)
+    }
+}
+
+/// 基于时间的一次性密码(RFC 6238)，HMAC-SHA1，30 秒，6 位
+///
+/// `secret` 是 base32 编码的密钥，忽略空格、大小写及末尾的 `=`
+pub fn totp(secret: &str, time: u64) -> Result<String, String> {
+    let secret = secret.replace(' ', "").to_uppercase();
+    let key = BASE32_NOPAD
+        .decode(secret.trim_end_matches('=').as_bytes())
+        .map_err(|e| format!("totp 不是有效的 base32: {}", e))?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).map_err(|e| e.to_string())?;
+    mac.update(&(time / TOTP_STEP).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    Ok(format!("{:06}", code % 1_000_000))
+}
+
+/// 保存验证码图片，并在终端中询问
+///
+/// 不在终端中运行时(比如计划任务)直接返回错误
+async fn prompt_captcha(user: &str, img: Bytes) -> Result<String, LoginError> {
+    let error = |e: String| LoginError::new(LoginFailure::Captcha, e);
+    if !std::io::stdin().is_terminal() {
+        return Err(error("需要图片验证码，但是没有在终端中运行".into()));
+    }
+
+    let _lock = PROMPT.lock().await;
+    let ext = image::guess_format(&img)
+        .ok()
+        .and_then(|f| f.extensions_str().first().copied())
+        .unwrap_or("png");
+    // 保存在自己的状态文件夹中，不使用公共的临时文件夹，
+    // 避免其他用户预先放置同名的符号链接
+    let dir = DIRS.state_dir();
+    let path = dir.join(format!("{}_captcha.{}", sanitize(user), ext));
+    let write = || -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        // 上次异常退出时留下的
+        let _r = std::fs::remove_file(&path);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(&img)
+    };
+    write().map_err(|e| error(format!("无法保存验证码图片: {}", e)))?;
+
+    let prompt = format!(
+        "{} 登陆需要验证码，图片已保存到: {}\n请输入验证码: ",
+        user,
+        path.display()
+    );
+    let answer = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        Ok(line.trim().to_string())
+    })
+    .await
+    .map_err(|e| error(e.to_string()))?
+    .map_err(|e| error(e.to_string()));
+    let _r = tokio::fs::remove_file(&path).await;
+
+    match answer? {
+        a if a.is_empty() => Err(error("没有输入验证码".into())),
+        a => Ok(a),
+    }
+}
+
+#[cfg(test)]
+mod login_test {
+    use super::*;
+
+    #[test]
+    fn classify_test() {
+        let page = |msg: &str| {
+            format!(
+                "<html><body><h2>登录失败！</h2><table><tr>\
+                <td class=\"text\">{}</td></tr></table></body></html>",
+                msg
+            )
+        };
+        let e = LoginError::classify(&page("用户名或密码不正确！")).unwrap();
+        assert_eq!(e.failure, LoginFailure::Credentials);
+        assert_eq!(e.message, "用户名或密码不正确！");
+
+        let cases = [
+            ("该账号已被禁用", LoginFailure::Disabled),
+            ("登录失败次数过多，密码错误", LoginFailure::Locked),
+            ("请输入正确的验证码", LoginFailure::Captcha),
+            ("Too many login attempts", LoginFailure::Locked),
+        ];
+        for (msg, failure) in cases {
+            assert_eq!(LoginError::classify(&page(msg)).unwrap().failure, failure);
+        }
+        assert!(LoginError::classify("<p>欢迎回来</p>").is_none());
+
+        // 重新显示的登陆表单中有验证码，脚本中有 `disabled`
+        let html = r#"<html><head>
+            <script>button.disabled = true;</script>
+            <style>.captcha { color: red }</style>
+        </head><body>
+            <table><tr><td class="text">用户名或密码不正确！</td></tr></table>
+            <form method="post" action="takelogin.php">
+                <input type="password" name="password">
+                验证码: <input type="text" name="imagestring">
+            </form>
+        </body></html>"#;
+        let e = LoginError::classify(html).unwrap();
+        assert_eq!(e.failure, LoginFailure::Credentials);
+        assert_eq!(e.message, "用户名或密码不正确！");
+        // 没有说明时查找页面，但是不包括脚本
+        let html = "<html><head><script>var disabled = 1;</script></head>\
+            <body><p>密码错误</p></body></html>";
+        let e = LoginError::classify(html).unwrap();
+        assert_eq!(e.failure, LoginFailure::Credentials);
+        assert_eq!(e.message, "");
+        assert!(!LoginFailure::Credentials.retryable());
+    }
+
+    #[test]
+    fn totp_test() {
+        // RFC 6238 附录B，密钥是 `12345678901234567890`
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        assert_eq!(totp(secret, 59).unwrap(), "287082");
+        assert_eq!(totp(&secret.to_lowercase(), 1111111109).unwrap(), "081804");
+        assert!(totp("not base32!", 0).is_err());
+    }
+
+    #[test]
+    fn login_form_test() {
+        let html = r#"<form method="post" action="takelogin.php">
+            <input type="text" name="username"><input type="password" name="password">
+            <img src="image.php?action=regimage&imagehash=abc">
+            <input type="text" name="imagestring">
+            <input type="hidden" name="imagehash" value="abc">
+        </form>"#;
+        let form = LoginForm::parse(html);
+        assert_eq!(
+            form.captcha,
+            Some((
+                "imagestring".into(),
+                "image.php?action=regimage&imagehash=abc".into()
+            ))
+        );
+        assert_eq!(form.hidden, vec![("imagehash".into(), "abc".into())]);
+        assert_eq!(form.totp, None);
+
+        assert!(form.password);
+
+        let html = r#"<form action="verify.php"><input name="two_step_code"></form>"#;
+        let form = LoginForm::parse(html);
+        assert_eq!(form.totp.as_deref(), Some("two_step_code"));
+        assert_eq!(form.action.as_deref(), Some("verify.php"));
+        assert!(!form.password);
+    }
+
+    #[tokio::test]
+    async fn optional_totp_test() {
+        let client = Session::new(&Default::default(), &Default::default(), Default::default())
+            .unwrap();
+        let page = Url::parse("https://pt.example.com/login.php").unwrap();
+        let user = UserConfig::default();
+
+        // NexusPHP 1.7 的登陆表单中总是有可选的两步验证码
+        let html = r#"<form method="post" action="takelogin.php">
+            <input type="text" name="username"><input type="password" name="password">
+            <input type="text" name="two_step_code">
+        </form>"#;
+        let form = LoginForm::parse(html);
+        assert!(form.answer(&client, &user, &page).await.unwrap().is_empty());
+
+        // 单独的两步验证页面必须填写
+        let html = r#"<form action="verify.php"><input name="two_step_code"></form>"#;
+        let e = LoginForm::parse(html).answer(&client, &user, &page).await.unwrap_err();
+        assert_eq!(e.failure, LoginFailure::TwoFactor);
+    }
+}
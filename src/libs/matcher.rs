@@ -0,0 +1,291 @@
+//! 图片相似度的计算方式
+//!
+//! - `dssim`: 结构相似度，准确但是比较慢
+//! - `phash`/`dhash`: 感知哈希，用汉明距离计算相似度，对裁剪、压缩不敏感
+//! - `ensemble`: 按权重组合 `dssim` 与 `phash`
+
+use crate::config::{CaptchaConfig, MatcherKind};
+use anyhow::{anyhow, Result};
+use dssim::{Dssim, DssimImage, ToRGBAPLU};
+use image::{imageops::FilterType, GrayImage};
+use imgref::Img;
+use load_image::ImageData;
+use std::sync::Arc;
+
+/// 预处理后的图片
+pub enum Features {
+    Dssim(DssimImage<f32>),
+    Hash(u64),
+    Many(Vec<Features>),
+}
+
+/// 相似度计算
+pub trait Matcher: Send + Sync {
+    /// 名字，用于日志
+    fn name(&self) -> String;
+
+    /// 预处理图片，题图只需要处理一次
+    fn prepare(&self, img: &[u8]) -> Result<Features>;
+
+    /// 百分制的相似度，越高越相似
+    fn compare(&self, orig: &Features, modif: &Features) -> Result<f64>;
+}
+
+/// 根据验证码设置创建
+pub fn from_config(config: &CaptchaConfig) -> Arc<dyn Matcher> {
+    let scales = config.scales();
+    match config.matcher() {
+        MatcherKind::Dssim => Arc::new(DssimMatcher::new(&scales)),
+        MatcherKind::Phash => Arc::new(HashMatcher::PHash),
+        MatcherKind::Dhash => Arc::new(HashMatcher::DHash),
+        MatcherKind::Ensemble => {
+            let weight = config.ensemble_weight();
+            Arc::new(Ensemble::new(vec![
+                (Box::new(DssimMatcher::new(&scales)), weight),
+                (Box::new(HashMatcher::PHash), 1.0 - weight),
+            ]))
+        }
+    }
+}
+
+/// DSSIM
+pub struct DssimMatcher {
+    attr: Dssim,
+}
+
+impl DssimMatcher {
+    /// `scales` 是每个尺度的权重
+    pub fn new(scales: &[f64]) -> Self {
+        let mut attr = Dssim::new();
+        attr.set_scales(scales);
+        Self { attr }
+    }
+}
+
+impl Default for DssimMatcher {
+    fn default() -> Self {
+        Self::new(&[100.0, 100.0])
+    }
+}
+
+impl Matcher for DssimMatcher {
+    fn name(&self) -> String {
+        "dssim".into()
+    }
+
+    fn prepare(&self, img: &[u8]) -> Result<Features> {
+        Ok(Features::Dssim(load_img(&self.attr, img)?))
+    }
+
+    fn compare(&self, orig: &Features, modif: &Features) -> Result<f64> {
+        let (Features::Dssim(orig), Features::Dssim(modif)) = (orig, modif) else {
+            return Err(anyhow!("dssim: 图片预处理方式不匹配"));
+        };
+        let (score, _) = self.attr.compare(orig, modif);
+        Ok(dssim_to_percent(score.into()))
+    }
+}
+
+/// 感知哈希
+pub enum HashMatcher {
+    /// 基于 DCT 低频部分
+    PHash,
+    /// 基于相邻像素的差值
+    DHash,
+}
+
+impl Matcher for HashMatcher {
+    fn name(&self) -> String {
+        match self {
+            Self::PHash => "phash".into(),
+            Self::DHash => "dhash".into(),
+        }
+    }
+
+    fn prepare(&self, img: &[u8]) -> Result<Features> {
+        let img = image::load_from_memory(img)?.to_luma8();
+        let hash = match self {
+            Self::PHash => phash(&img),
+            Self::DHash => dhash(&img),
+        };
+        Ok(Features::Hash(hash))
+    }
+
+    fn compare(&self, orig: &Features, modif: &Features) -> Result<f64> {
+        let (Features::Hash(orig), Features::Hash(modif)) = (orig, modif) else {
+            return Err(anyhow!("{}: 图片预处理方式不匹配", self.name()));
+        };
+        Ok(hamming_to_percent((orig ^ modif).count_ones()))
+    }
+}
+
+/// 按权重组合多个
+pub struct Ensemble {
+    matchers: Vec<(Box<dyn Matcher>, f64)>,
+}
+
+impl Ensemble {
+    pub fn new(matchers: Vec<(Box<dyn Matcher>, f64)>) -> Self {
+        Self { matchers }
+    }
+}
+
+impl Matcher for Ensemble {
+    fn name(&self) -> String {
+        let names = self
+            .matchers
+            .iter()
+            .map(|(m, w)| format!("{}*{:.2}", m.name(), w))
+            .collect::<Vec<_>>()
+            .join("+");
+        format!("ensemble[{}]", names)
+    }
+
+    fn prepare(&self, img: &[u8]) -> Result<Features> {
+        let features = self
+            .matchers
+            .iter()
+            .map(|(m, _)| m.prepare(img))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Features::Many(features))
+    }
+
+    fn compare(&self, orig: &Features, modif: &Features) -> Result<f64> {
+        let (Features::Many(orig), Features::Many(modif)) = (orig, modif) else {
+            return Err(anyhow!("ensemble: 图片预处理方式不匹配"));
+        };
+        let mut total = 0.0;
+        let mut weights = 0.0;
+        for (((m, w), o), p) in self.matchers.iter().zip(orig).zip(modif) {
+            total += m.compare(o, p)? * w;
+            weights += w;
+        }
+        if weights <= 0.0 {
+            return Err(anyhow!("ensemble: 权重之和必须大于0"));
+        }
+        Ok(total / weights)
+    }
+}
+
+fn load_img(attr: &Dssim, m_b: &[u8]) -> Result<DssimImage<f32>> {
+    let img = load_image::load_data(m_b)?;
+
+    let res = match img.bitmap {
+        ImageData::RGB8(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
+        }
+        ImageData::RGB16(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
+        }
+        ImageData::RGBA8(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
+        }
+        ImageData::RGBA16(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
+        }
+        ImageData::GRAY8(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
+        }
+        ImageData::GRAY16(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
+        }
+        ImageData::GRAYA8(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
+        }
+        ImageData::GRAYA16(ref bitmap) => {
+            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
+        }
+    };
+    match res {
+        None => Err(anyhow!("error!!!")),
+        Some(i) => Ok(i),
+    }
+}
+
+// 将DSSIM结果转换为百分制，越高越相似
+fn dssim_to_percent(dssim: f64) -> f64 {
+    (1.0 - 2.0 * dssim.atan() / std::f64::consts::PI) * 100.0
+}
+
+// 将64位哈希的汉明距离转换为百分制，越高越相似
+fn hamming_to_percent(distance: u32) -> f64 {
+    (1.0 - distance as f64 / 64.0) * 100.0
+}
+
+/// 缩小到 32x32 后做 DCT，取左上角 8x8 的低频部分与中位数比较
+fn phash(img: &GrayImage) -> u64 {
+    const N: usize = 32;
+    let small = image::imageops::resize(img, N as u32, N as u32, FilterType::Triangle);
+    let pixels: Vec<f64> = small.pixels().map(|p| p.0[0] as f64).collect();
+
+    let cos: Vec<f64> = (0..8 * N)
+        .map(|i| {
+            let (u, x) = (i / N, i % N);
+            ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * N) as f64).cos()
+        })
+        .collect();
+
+    let mut low = [0.0; 64];
+    for (v, row) in low.chunks_mut(8).enumerate() {
+        for (u, coeff) in row.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for y in 0..N {
+                for x in 0..N {
+                    sum += pixels[y * N + x] * cos[u * N + x] * cos[v * N + y];
+                }
+            }
+            *coeff = sum;
+        }
+    }
+
+    // 不包括直流分量
+    let mut sorted = low[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+
+    let mut hash = 0;
+    for (i, c) in low.iter().enumerate() {
+        if *c > median {
+            hash |= 1 << i;
+        }
+    }
+    hash
+}
+
+/// 缩小到 9x8，比较每行相邻的像素
+fn dhash(img: &GrayImage) -> u64 {
+    let small = image::imageops::resize(img, 9, 8, FilterType::Triangle);
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1 << (y * 8 + x);
+            }
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod matcher_test {
+    use super::*;
+    use image::Luma;
+
+    #[test]
+    fn percen_test() {
+        assert!(dssim_to_percent(0.1) >= 93.0);
+        assert_eq!(hamming_to_percent(0), 100.0);
+        assert_eq!(hamming_to_percent(64), 0.0);
+    }
+
+    #[test]
+    fn hash_test() {
+        let gradient = GrayImage::from_fn(120, 200, |x, y| Luma([(x + y) as u8]));
+        let inverted = GrayImage::from_fn(120, 200, |x, y| Luma([255 - (x + y) as u8]));
+
+        assert_eq!(phash(&gradient), phash(&gradient));
+        assert_eq!(dhash(&gradient), dhash(&gradient));
+        assert!((phash(&gradient) ^ phash(&inverted)).count_ones() > 16);
+        assert!((dhash(&gradient) ^ dhash(&inverted)).count_ones() > 16);
+    }
+}
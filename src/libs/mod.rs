@@ -1,6 +1,16 @@
+pub mod archive;
+pub mod bench;
 pub mod bot;
+pub mod cache;
 pub mod cliparser;
 pub mod command;
 pub mod config;
+pub mod cookies;
 pub mod email_bot;
+pub mod http;
+pub mod login;
+pub mod matcher;
+pub mod outcome;
 pub mod picparser;
+pub mod poster;
+pub mod site;
@@ -0,0 +1,319 @@
+//! 签到的结果
+//!
+//! 重试、邮件提醒及退出码都根据结果决定
+
+use crate::{http::Throttled, login::LoginFailure, picparser::Scored, site::Reward};
+use std::{fmt::Display, time::Duration};
+
+/// 选择的答案
+#[derive(Debug, Clone)]
+pub struct Chosen {
+    /// 选项的文字
+    pub name: String,
+    /// 提交的值
+    pub value: String,
+    /// 百分制的相似度
+    pub score: f64,
+}
+
+impl From<&Scored> for Chosen {
+    fn from(s: &Scored) -> Self {
+        Self {
+            name: s.answer.name.clone(),
+            value: s.answer.value.clone(),
+            score: s.score,
+        }
+    }
+}
+
+impl Display for Chosen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:.2}%", self.name, self.score)
+    }
+}
+
+/// 签到失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// 登陆失败及原因
+    Login(LoginFailure),
+    /// 网络错误，比如超时、无法连接
+    Network,
+    /// 站点或海报来源要求稍后再试，HTTP 429 或 503
+    Throttled,
+    /// 无法获取任何海报，比如豆瓣不可用
+    Poster,
+    /// 答案不确定，没有提交
+    Ambiguous,
+    /// 提交了答案，但是站点返回失败
+    Rejected,
+    /// 需要补签，但是不允许补签
+    MakeUp,
+    /// 其他错误，比如无法识别签到页面
+    Other,
+}
+
+impl Failure {
+    /// 请求出错时归为网络错误，服务器要求稍后再试时归为 `Throttled`，否则使用 `self`
+    pub fn or_network(self, e: &anyhow::Error) -> Self {
+        if Throttled::find(e).is_some() {
+            return Self::Throttled;
+        }
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() || e.is_connect() || e.is_request() => Self::Network,
+            _ => self,
+        }
+    }
+
+    /// 是否值得重试
+    ///
+    /// 只有网络错误、请求过于频繁及无法获取海报这类暂时的错误才重试；
+    /// 密码错误等登陆失败重试也没用，还可能导致账号被锁定；
+    /// 答案不确定或错误时重试会再次提交，补签的设置在一次运行中也不会改变
+    pub fn retryable(&self) -> bool {
+        match self {
+            Self::Login(login) => login.retryable(),
+            Self::Network | Self::Throttled | Self::Poster => true,
+            Self::Ambiguous | Self::Rejected | Self::MakeUp | Self::Other => false,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Login(login) => return write!(f, "登陆失败，{}", login),
+            Self::Network => "网络错误",
+            Self::Throttled => "请求过于频繁",
+            Self::Poster => "无法获取海报",
+            Self::Ambiguous => "答案不确定",
+            Self::Rejected => "答案错误",
+            Self::MakeUp => "需要补签",
+            Self::Other => "其他错误",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// 签到的结果
+#[derive(Debug, Clone)]
+pub enum AttendanceOutcome {
+    /// 签到成功，`answer` 为 `None` 表示访问签到页面即完成签到
+    Success {
+        attempts: u8,
+        answer: Option<Chosen>,
+        reward: Reward,
+    },
+    /// 今天已经签到过了，不算失败
+    AlreadyAttended { attempts: u8 },
+    /// 签到失败，`reason` 是最后一次的错误，
+    /// `answer` 是提交了的或者没有把握提交的答案，
+    /// `retry_after` 是服务器要求等待的时间
+    Failed {
+        attempts: u8,
+        failure: Failure,
+        reason: String,
+        answer: Option<Chosen>,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl AttendanceOutcome {
+    /// 一次尝试的失败
+    pub fn failed(failure: Failure, e: anyhow::Error, answer: Option<Chosen>) -> Self {
+        Self::Failed {
+            attempts: 1,
+            failure: failure.or_network(&e),
+            reason: e.to_string(),
+            answer,
+            retry_after: Throttled::find(&e).and_then(|t| t.retry_after),
+        }
+    }
+
+    /// 尝试的次数
+    pub fn attempts(&self) -> u8 {
+        match self {
+            Self::Success { attempts, .. }
+            | Self::AlreadyAttended { attempts }
+            | Self::Failed { attempts, .. } => *attempts,
+        }
+    }
+
+    /// 设置尝试的次数
+    pub fn with_attempts(mut self, n: u8) -> Self {
+        match self {
+            Self::Success {
+                ref mut attempts, ..
+            }
+            | Self::AlreadyAttended { ref mut attempts }
+            | Self::Failed {
+                ref mut attempts, ..
+            } => *attempts = n,
+        }
+        self
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Self::Failed { .. })
+    }
+
+    /// 失败并且值得重试
+    pub fn retryable(&self) -> bool {
+        match self {
+            Self::Failed { failure, .. } => failure.retryable(),
+            _ => false,
+        }
+    }
+
+    /// 是否发送邮件提醒
+    ///
+    /// 失败时提醒，成功时按照 `on_success` 提醒，已经签到过了不提醒
+    pub fn notify(&self, on_success: bool) -> bool {
+        match self {
+            Self::Success { .. } => on_success,
+            Self::AlreadyAttended { .. } => false,
+            Self::Failed { .. } => true,
+        }
+    }
+
+    /// 服务器要求重试前等待的时间
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Failed { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl Display for AttendanceOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Success {
+                attempts,
+                answer,
+                reward,
+            } => {
+                write!(f, "签到成功(尝试 {} 次)", attempts)?;
+                if let Some(a) = answer {
+                    write!(f, " 答案: {}", a)?;
+                }
+                if !reward.is_empty() {
+                    write!(f, " {}", reward)?;
+                }
+                Ok(())
+            }
+            Self::AlreadyAttended { .. } => write!(f, "今天已经签到过了"),
+            Self::Failed {
+                attempts,
+                failure,
+                reason,
+                answer,
+                ..
+            } => {
+                write!(f, "签到失败({}，尝试 {} 次): {}", failure, attempts, reason)?;
+                match answer {
+                    Some(a) => write!(f, " 答案: {}", a),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod outcome_test {
+    use super::*;
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn retryable_test() {
+        let table = [
+            (Failure::Network, true),
+            (Failure::Throttled, true),
+            (Failure::Poster, true),
+            (Failure::Login(LoginFailure::Transport), true),
+            (Failure::Login(LoginFailure::Unknown), true),
+            (Failure::Login(LoginFailure::Credentials), false),
+            (Failure::Login(LoginFailure::Disabled), false),
+            (Failure::Login(LoginFailure::Locked), false),
+            (Failure::Login(LoginFailure::Captcha), false),
+            (Failure::Login(LoginFailure::TwoFactor), false),
+            (Failure::Ambiguous, false),
+            (Failure::Rejected, false),
+            (Failure::MakeUp, false),
+            (Failure::Other, false),
+        ];
+        for (failure, retryable) in table {
+            assert_eq!(failure.retryable(), retryable, "{:?}", failure);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_test() {
+        let outcome = AttendanceOutcome::failed(Failure::Rejected, anyhow!("答案错误"), None);
+        assert!(matches!(
+            outcome,
+            AttendanceOutcome::Failed {
+                failure: Failure::Rejected,
+                retry_after: None,
+                ..
+            }
+        ));
+
+        // 错误链中的 Throttled 优先，并记录等待的时间
+        let throttled = Throttled {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::from_secs(30)),
+        };
+        let e = anyhow::Error::new(throttled).context("无法获取海报");
+        let outcome = AttendanceOutcome::failed(Failure::Poster, e, None);
+        assert!(matches!(
+            outcome,
+            AttendanceOutcome::Failed {
+                failure: Failure::Throttled,
+                ..
+            }
+        ));
+        assert_eq!(outcome.retry_after(), Some(Duration::from_secs(30)));
+
+        // 无法连接
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let e = client.get("http://127.0.0.1:1/").send().await.unwrap_err();
+        let e = anyhow::Error::new(e).context("无法访问签到页面");
+        assert_eq!(Failure::Other.or_network(&e), Failure::Network);
+        // 其他的 reqwest 错误不是网络错误
+        let e = client.get("not a url").send().await.unwrap_err();
+        assert_eq!(Failure::Other.or_network(&e.into()), Failure::Other);
+    }
+
+    #[test]
+    fn outcome_test() {
+        let success = AttendanceOutcome::Success {
+            attempts: 1,
+            answer: None,
+            reward: Reward::default(),
+        };
+        assert!(!success.is_failure());
+        assert!(!success.retryable());
+        assert!(!success.notify(false));
+        assert!(success.notify(true));
+
+        // 已经签到过了不算失败，也不提醒
+        let attended = AttendanceOutcome::AlreadyAttended { attempts: 1 };
+        assert!(!attended.is_failure());
+        assert!(!attended.retryable());
+        assert!(!attended.notify(false));
+        assert!(!attended.notify(true));
+
+        let failed = AttendanceOutcome::failed(Failure::Network, anyhow!("超时"), None);
+        assert!(failed.is_failure());
+        assert!(failed.retryable());
+        assert!(failed.notify(false));
+        assert_eq!(failed.with_attempts(3).attempts(), 3);
+
+        let failed = AttendanceOutcome::failed(Failure::MakeUp, anyhow!("需要补签"), None);
+        assert!(failed.is_failure());
+        assert!(!failed.retryable());
+    }
+}
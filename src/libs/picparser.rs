@@ -1,18 +1,75 @@
 //! 解析图片，获得答案
 
+use crate::config::{CaptchaConfig, ResizeFilter};
+use crate::http::{Session, Throttled};
+use crate::matcher::{Features, Matcher};
+use crate::poster::{PosterChain, PosterMeta};
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use dssim::{Dssim, DssimImage, ToRGBAPLU};
-use image::ImageFormat;
-use imgref::Img;
-use load_image::ImageData;
-use reqwest::Client;
-use serde::Deserialize;
-use std::{fmt::Display, io::Cursor};
+use image::{imageops::FilterType, ImageFormat, Rgb, RgbImage};
+use reqwest::header::CONTENT_TYPE;
+use std::{fmt::Display, io::Cursor, sync::Arc};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+/// 同时获取海报的最大数量
+const MAX_FETCHES: usize = 4;
+
+/// 支持的图片格式，由 cargo features 决定
+const SUPPORTED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    #[cfg(feature = "png")]
+    ImageFormat::Png,
+    #[cfg(feature = "webp")]
+    ImageFormat::WebP,
+    #[cfg(feature = "gif")]
+    ImageFormat::Gif,
+];
+
+/// 缩放图片的方式
+#[derive(Clone, Copy, Debug)]
+pub struct Resize {
+    pub width: u32,
+    pub height: u32,
+    pub filter: FilterType,
+    /// 保持宽高比，不足的部分用白色填充
+    pub keep_aspect: bool,
+}
+
+impl From<&CaptchaConfig> for Resize {
+    fn from(value: &CaptchaConfig) -> Self {
+        let (width, height) = value.size();
+        Self {
+            width,
+            height,
+            filter: value.filter().into(),
+            keep_aspect: value.keep_aspect(),
+        }
+    }
+}
+
+impl Default for Resize {
+    fn default() -> Self {
+        Self::from(&CaptchaConfig::default())
+    }
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(value: ResizeFilter) -> Self {
+        match value {
+            ResizeFilter::Nearest => Self::Nearest,
+            ResizeFilter::Triangle => Self::Triangle,
+            ResizeFilter::CatmullRom => Self::CatmullRom,
+            ResizeFilter::Gaussian => Self::Gaussian,
+            ResizeFilter::Lanczos3 => Self::Lanczos3,
+        }
+    }
+}
 
 /// 验证码
 pub struct Kaptcha {
     pub url: String,
+    /// 原始图片
+    pub raw_bytes: Option<Bytes>,
     pub img_bytes: Option<Bytes>,
 }
 
@@ -20,64 +77,281 @@ impl Kaptcha {
     pub fn new(url: String) -> Self {
         Self {
             url,
+            raw_bytes: None,
             img_bytes: None,
         }
     }
 
     /// 获取图片，并且保存在内存里
-    async fn get_img(&mut self, client: &Client) -> Result<()> {
+    async fn get_img(&mut self, client: &Session, resize: Resize) -> Result<()> {
         if self.img_bytes.is_none() {
-            let b = client.get(self.url.as_str()).send().await?.bytes().await?;
-            let b = reseize_pic(b)?;
+            let res = client.get(self.url.as_str()).send().await?;
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let b = res.bytes().await?;
+            self.raw_bytes = Some(b.clone());
+            let b = reseize_pic(b, content_type.as_deref(), resize)?;
             self.img_bytes = Some(b);
         }
         Ok(())
     }
 
-    /// 与答案相比较
+    /// 与所有答案相比较
+    ///
+    /// 并发获取所有选项的海报，同时最多 `MAX_FETCHES` 个，
+    /// 每个选项最多比较 `candidates` 张候选海报，取相似度最高的，
+    /// 相似度的计算放在阻塞线程池里进行
+    ///
+    /// 返回按相似度从高到低排列的结果，
+    /// 所有选项都无法比较时也返回 `Ok`，由 `Ranking::pick` 处理
     pub async fn compare_with_answers(
         &mut self,
-        answers: &mut [Answer],
-        client: &Client,
-        limit: f64,
-    ) -> Result<Answer> {
-        let mut attr = Dssim::new();
-        attr.set_scales(&[100.0, 100.0]);
-        self.get_img(client).await?;
-        // log::debug!("设置的阈值: {}", limit);
-        let Some(ref ori) = self.img_bytes else {
+        answers: &[Answer],
+        client: &Session,
+        posters: &Arc<PosterChain>,
+        matcher: Arc<dyn Matcher>,
+        resize: Resize,
+        candidates: usize,
+    ) -> Result<Ranking> {
+        self.get_img(client, resize).await?;
+        let Some(ori) = self.img_bytes.clone() else {
             return Err(anyhow!("无法获取题图"));
         };
-        let Ok(orig) = load_img(&attr, ori) else {
-            return Err(anyhow!("无法获取题图的ssimimg"));
+        let orig = {
+            let matcher = matcher.clone();
+            tokio::task::spawn_blocking(move || matcher.prepare(&ori)).await?
         };
+        let Ok(orig) = orig.map(Arc::new) else {
+            return Err(anyhow!("无法预处理题图: {}", matcher.name()));
+        };
+
+        let semaphore = Arc::new(Semaphore::new(MAX_FETCHES));
+        let mut tasks = JoinSet::new();
+        for answer in answers.iter().cloned() {
+            let (posters, semaphore) = (posters.clone(), semaphore.clone());
+            let (matcher, orig) = (matcher.clone(), orig.clone());
+            let _r = tasks.spawn(async move {
+                let mut answer = answer;
+                let res = score_answer(
+                    &mut answer,
+                    &posters,
+                    &semaphore,
+                    matcher,
+                    orig,
+                    resize,
+                    candidates,
+                )
+                .await;
+                (answer, res)
+            });
+        }
+
+        let mut scores = vec![];
+        let mut failed = vec![];
+        while let Some(res) = tasks.join_next().await {
+            match res {
+                Ok((answer, Ok(score))) => {
+                    let scored = Scored { answer, score };
+                    log::debug!("选项: {}", scored);
+                    scores.push(scored);
+                }
+                Ok((answer, Err(error))) => {
+                    log::warn!("无法比较选项: {}, Err: {}", answer.name, error);
+                    failed.push(Failed { answer, error });
+                }
+                Err(e) => log::warn!("比较任务失败: {}", e),
+            }
+        }
 
-        // TODO 在这里改成异步，或者多线程
-        // 应该能减少很多时间
-        for i in answers.iter_mut() {
-            if let Err(e) = i.get_img(client).await {
-                log::warn!("无法获取海报: {}, Err: {}", i.name, e);
-                continue;
-            } else {
-                let Some(ref pic) = i.img_bytes else {
-                    log::debug!("无法获取选项图的img");
-                    continue;
-                };
-                let Ok(modif) = &load_img(&attr,pic) else {
-                    log::debug!("无法获取选项图的img");
-                    continue;
-                };
-                let (score, _) = attr.compare(&orig, modif);
-
-                let score = dssim_to_percent(score.into());
-                if score >= limit {
-                    log::info!("获取答案: {} 相似度: {}%", i.name.as_str(), score);
-                    return Ok(i.clone());
+        Ok(Ranking::new(scores).with_failed(failed))
+    }
+}
+
+/// 获取选项的候选海报，以相似度最高的一张作为选项的相似度
+async fn score_answer(
+    answer: &mut Answer,
+    posters: &PosterChain,
+    semaphore: &Semaphore,
+    matcher: Arc<dyn Matcher>,
+    orig: Arc<Features>,
+    resize: Resize,
+    candidates: usize,
+) -> Result<f64> {
+    {
+        let _permit = semaphore.acquire().await?;
+        answer.get_img(posters, resize, candidates).await?;
+    }
+    let pics: Vec<Bytes> = answer
+        .candidates
+        .iter()
+        .map(|c| c.img_bytes.clone())
+        .collect();
+
+    let results = tokio::task::spawn_blocking(move || -> Vec<Result<f64>> {
+        pics.iter()
+            .map(|pic| {
+                let modif = matcher.prepare(pic)?;
+                matcher.compare(&orig, &modif)
+            })
+            .collect()
+    })
+    .await?;
+
+    let mut best: Option<(usize, f64)> = None;
+    let mut error = None;
+    for (i, res) in results.into_iter().enumerate() {
+        let poster = &answer.candidates[i].poster;
+        match res {
+            Ok(score) => {
+                log::debug!("{} 候选海报: {} {:.2}%", answer.name, poster, score);
+                match best {
+                    Some((_, b)) if b >= score => {}
+                    _ => best = Some((i, score)),
                 }
             }
+            Err(e) => {
+                log::debug!("{} 无法比较候选海报: {}, Err: {}", answer.name, poster, e);
+                error = Some(e);
+            }
+        }
+    }
+
+    let Some((i, score)) = best else {
+        answer.choose(0);
+        return Err(error.unwrap_or_else(|| anyhow!("没有候选海报")));
+    };
+    answer.choose(i);
+    Ok(score)
+}
+
+/// 单个选项的相似度
+#[derive(Clone)]
+pub struct Scored {
+    pub answer: Answer,
+    /// 百分制，越高越相似
+    pub score: f64,
+}
+
+impl Display for Scored {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.answer.poster {
+            None => write!(f, "{}: {:.2}%", self.answer.name, self.score),
+            Some(ref p) => write!(f, "{} {}: {:.2}%", self.answer.name, p, self.score),
+        }
+    }
+}
+
+/// 无法比较的选项
+pub struct Failed {
+    pub answer: Answer,
+    pub error: anyhow::Error,
+}
+
+/// 所有选项的比较结果，按相似度从高到低排列
+pub struct Ranking {
+    scores: Vec<Scored>,
+    failed: Vec<Failed>,
+}
+
+impl Ranking {
+    pub fn new(mut scores: Vec<Scored>) -> Self {
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Self {
+            scores,
+            failed: vec![],
+        }
+    }
+
+    /// 记录无法比较的选项
+    pub fn with_failed(mut self, failed: Vec<Failed>) -> Self {
+        self.failed = failed;
+        self
+    }
+
+    pub fn scores(&self) -> &[Scored] {
+        &self.scores
+    }
+
+    pub fn failed(&self) -> &[Failed] {
+        &self.failed
+    }
+
+    /// 所有选项，包括无法比较的
+    pub fn answers(&self) -> impl Iterator<Item = &Answer> {
+        self.scores
+            .iter()
+            .map(|s| &s.answer)
+            .chain(self.failed.iter().map(|f| &f.answer))
+    }
+
+    /// 相似度最高的选项
+    pub fn best(&self) -> Option<&Scored> {
+        self.scores.first()
+    }
+
+    /// 相似度第二的选项
+    pub fn runner_up(&self) -> Option<&Scored> {
+        self.scores.get(1)
+    }
+
+    /// 第一名领先第二名的幅度
+    ///
+    /// 只有一个选项时就是它自己的相似度
+    pub fn lead(&self) -> f64 {
+        match (self.best(), self.runner_up()) {
+            (Some(b), Some(r)) => b.score - r.score,
+            (Some(b), None) => b.score,
+            _ => 0.0,
+        }
+    }
+
+    /// 选出答案
+    ///
+    /// 第一名需要达到阈值 `limit`，并且领先第二名至少 `margin`，
+    /// 否则认为结果不明确，不应该提交
+    ///
+    /// 有选项因为海报来源要求稍后再试而无法比较时，错误链中带有 [`Throttled`]
+    pub fn pick(&self, limit: f64, margin: f64) -> Result<&Scored> {
+        let error = |msg: String| {
+            let throttled = self.failed.iter().find_map(|f| Throttled::find(&f.error));
+            match throttled {
+                Some(t) => anyhow::Error::new(t.clone()).context(msg),
+                None => anyhow!(msg),
+            }
+        };
+        let Some(best) = self.best() else {
+            return Err(error("所有比较均失败了".into()));
+        };
+        if best.score < limit {
+            return Err(error(format!(
+                "ambiguous: 最高相似度未达到阈值 {:.2}% < {:.2}% [{}]",
+                best.score, limit, self
+            )));
         }
+        if self.lead() < margin {
+            return Err(error(format!(
+                "ambiguous: 领先幅度不足 {:.2}% < {:.2}% [{}]",
+                self.lead(),
+                margin,
+                self
+            )));
+        }
+        Ok(best)
+    }
+}
 
-        Err(anyhow!("所有比较均失败了"))
+impl Display for Ranking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = self
+            .scores
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{}", s)
     }
 }
 
@@ -87,12 +361,28 @@ impl From<String> for Kaptcha {
     }
 }
 
+/// 选项的一张候选海报
+#[derive(Clone)]
+pub struct Candidate {
+    pub poster: PosterMeta,
+    /// 原始海报
+    pub raw_bytes: Bytes,
+    /// 下载时的 `Content-Type`
+    pub content_type: Option<String>,
+    pub img_bytes: Bytes,
+}
+
 #[derive(Clone)]
 pub struct Answer {
     pub name: String,
     pub value: String,
-    pub img_url: Option<String>,
+    /// 与题图最相似的候选海报
+    pub poster: Option<PosterMeta>,
+    /// 原始海报
+    pub raw_bytes: Option<Bytes>,
     pub img_bytes: Option<Bytes>,
+    /// 所有候选海报
+    pub candidates: Vec<Candidate>,
 }
 
 impl Answer {
@@ -100,110 +390,102 @@ impl Answer {
         Self {
             name,
             value,
-            img_url: None,
+            poster: None,
+            raw_bytes: None,
             img_bytes: None,
+            candidates: vec![],
         }
     }
 
-    pub async fn get_img(&mut self, client: &Client) -> Result<()> {
-        let data = get_douban_data(&self.name, client).await?;
-        // log::debug!("获取到的豆瓣信息: {}", data);
-        self.img_url = Some(data.img);
-        let Some(ref url) = self.img_url else {
-            return Err(anyhow!("无法获取图片"));
-        };
-        let b = client.get(url).send().await?.bytes().await?;
-
-        let b = reseize_pic(b)?;
+    /// 从海报来源获取最多 `n` 张候选海报
+    pub async fn get_img(&mut self, posters: &PosterChain, resize: Resize, n: usize) -> Result<()> {
+        let mut error = None;
+        for (meta, poster) in posters.fetch(&self.name, n).await? {
+            let b = poster.bytes;
+            match reseize_pic(b.clone(), poster.content_type.as_deref(), resize) {
+                Ok(img) => {
+                    log::debug!("{} 获取到海报: {}", self.name, meta);
+                    self.candidates.push(Candidate {
+                        poster: meta,
+                        raw_bytes: b,
+                        content_type: poster.content_type,
+                        img_bytes: img,
+                    });
+                }
+                Err(e) => {
+                    log::debug!("{} 无法处理海报: {}, Err: {}", self.name, meta, e);
+                    error = Some(e);
+                }
+            }
+        }
 
-        self.img_bytes = Some(b);
+        if self.candidates.is_empty() {
+            return Err(error.unwrap_or_else(|| anyhow!("无法获取选项的海报")));
+        }
         Ok(())
     }
-}
-
-impl From<(String, String)> for Answer {
-    fn from(value: (String, String)) -> Self {
-        Self::new(value.0, value.1)
-    }
-}
-
-#[derive(Deserialize)]
-struct DouBanData {
-    /// 图片链接
-    img: String,
-    title: String,
-    sub_title: Option<String>,
-}
 
-impl Display for DouBanData {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.sub_title {
-            None => write!(f, "DoubanData[{}]", self.title),
-            Some(subtitle) => write!(f, "DoubanData[{}-{}]", self.title, subtitle),
+    /// 选定第 `i` 张候选海报
+    fn choose(&mut self, i: usize) {
+        if let Some(c) = self.candidates.get(i) {
+            self.poster = Some(c.poster.clone());
+            self.raw_bytes = Some(c.raw_bytes.clone());
+            self.img_bytes = Some(c.img_bytes.clone());
         }
     }
 }
 
-async fn get_douban_data(name: &str, client: &Client) -> Result<DouBanData> {
-    let res: Vec<DouBanData> = client
-        .get("https://movie.douban.com/j/subject_suggest")
-        .query(&[("q", name)])
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    // log::debug!("豆瓣数据: {}个", res.len());
-
-    if let Some(d) = res.into_iter().next() {
-        Ok(d)
-    } else {
-        Err(anyhow!("无法获取豆瓣数据"))
+impl From<(String, String)> for Answer {
+    fn from(value: (String, String)) -> Self {
+        Self::new(value.0, value.1)
     }
 }
 
-fn load_img(attr: &Dssim, m_b: &Bytes) -> Result<DssimImage<f32>> {
-    let img = load_image::load_data(m_b)?;
-
-    let res = match img.bitmap {
-        ImageData::RGB8(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
-        }
-        ImageData::RGB16(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
-        }
-        ImageData::RGBA8(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
-        }
-        ImageData::RGBA16(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
-        }
-        ImageData::GRAY8(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
-        }
-        ImageData::GRAY16(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgblu(), img.width, img.height))
-        }
-        ImageData::GRAYA8(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
-        }
-        ImageData::GRAYA16(ref bitmap) => {
-            attr.create_image(&Img::new(bitmap.to_rgbaplu(), img.width, img.height))
-        }
+/// 判断图片格式
+///
+/// 优先根据内容判断，无法判断时参考 `Content-Type`
+fn detect_format(b: &[u8], content_type: Option<&str>) -> Result<ImageFormat> {
+    let format = image::guess_format(b)
+        .ok()
+        .or_else(|| content_type.and_then(ImageFormat::from_mime_type));
+    let Some(format) = format else {
+        return Err(anyhow!(
+            "无法判断图片格式, Content-Type: {}",
+            content_type.unwrap_or("无")
+        ));
     };
-    match res {
-        None => Err(anyhow!("error!!!")),
-        Some(i) => Ok(i),
+
+    if !SUPPORTED_FORMATS.contains(&format) {
+        return Err(anyhow!("不支持的图片格式: {:?}", format));
     }
+    Ok(format)
 }
 
 /// 设置图片尺寸
-fn reseize_pic(pic1: Bytes) -> Result<Bytes> {
+///
+/// 统一转换为 jpeg 格式
+pub fn reseize_pic(pic1: Bytes, content_type: Option<&str>, resize: Resize) -> Result<Bytes> {
+    let format = detect_format(&pic1, content_type)?;
     let mut reader = image::io::Reader::new(Cursor::new(pic1));
-    reader.set_format(image::ImageFormat::Jpeg);
-    let img = reader.decode()?;
+    reader.set_format(format);
+    let img = reader
+        .decode()
+        .map_err(|e| anyhow!("无法解码图片({:?}): {}", format, e))?
+        .to_rgb8();
 
-    let img = image::imageops::resize(&img, 120, 200, image::imageops::FilterType::Nearest);
+    let img = if resize.keep_aspect {
+        // 等比缩放后居中，保证所有图片尺寸一致
+        let fitted = image::DynamicImage::ImageRgb8(img)
+            .resize(resize.width, resize.height, resize.filter)
+            .to_rgb8();
+        let mut canvas = RgbImage::from_pixel(resize.width, resize.height, Rgb([255, 255, 255]));
+        let x = (resize.width - fitted.width()) / 2;
+        let y = (resize.height - fitted.height()) / 2;
+        image::imageops::overlay(&mut canvas, &fitted, x.into(), y.into());
+        canvas
+    } else {
+        image::imageops::resize(&img, resize.width, resize.height, resize.filter)
+    };
 
     let mut buf = Vec::new();
     img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Jpeg)?;
@@ -211,16 +493,59 @@ fn reseize_pic(pic1: Bytes) -> Result<Bytes> {
     Ok(buf.into())
 }
 
-// 将DSSIM结果转换为百分制，越高越相似
-fn dssim_to_percent(dssim: f64) -> f64 {
-    (1.0 - 2.0 * dssim.atan() / std::f64::consts::PI) * 100.0
-}
-
 #[cfg(test)]
 mod pic_test {
     use super::*;
+
+    fn scored(name: &str, score: f64) -> Scored {
+        Scored {
+            answer: Answer::new(name.into(), name.into()),
+            score,
+        }
+    }
+
     #[test]
-    fn percen_test() {
-        assert!(dssim_to_percent(0.1) >= 93.0);
+    fn ranking_test() {
+        let ranking = Ranking::new(vec![
+            scored("a", 94.0),
+            scored("b", 99.0),
+            scored("c", 60.0),
+        ]);
+        assert_eq!(ranking.best().unwrap().answer.name, "b");
+        assert_eq!(ranking.runner_up().unwrap().answer.name, "a");
+        assert_eq!(ranking.pick(93.0, 2.0).unwrap().answer.name, "b");
+        // 领先幅度不够
+        assert!(ranking.pick(93.0, 10.0).is_err());
+        // 达不到阈值
+        assert!(ranking.pick(99.5, 2.0).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "png")]
+    fn format_test() {
+        let img = image::RgbImage::new(4, 4);
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        assert_eq!(detect_format(&png, None).unwrap(), ImageFormat::Png);
+        // 无法从内容判断时参考 Content-Type
+        assert_eq!(
+            detect_format(b"???", Some("image/jpeg")).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert!(detect_format(b"???", None).is_err());
+
+        let resized = reseize_pic(png.clone().into(), None, Resize::default()).unwrap();
+        assert_eq!(detect_format(&resized, None).unwrap(), ImageFormat::Jpeg);
+
+        // 保持宽高比时尺寸也一致
+        let resize = Resize {
+            keep_aspect: true,
+            ..Default::default()
+        };
+        let resized = reseize_pic(png.into(), None, resize).unwrap();
+        let img = image::load_from_memory(&resized).unwrap();
+        assert_eq!((img.width(), img.height()), (120, 200));
     }
 }
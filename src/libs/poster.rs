@@ -0,0 +1,691 @@
+//! 海报来源
+//!
+//! 根据选项的名字获取对应的海报，按照配置的顺序依次尝试
+//!
+//! 同名的翻拍、续集、剧集很常见，所以每个选项会获取多张候选海报
+//!
+//! 所有用户共用请求频率限制，多个用户同时查找同一个选项时只请求一次
+
+use crate::cache::{normalize_title, PosterCache};
+use crate::config::{
+    BackoffConfig, HttpConfig, PosterSourceConfig, ProxyConfig, RateLimitConfig,
+};
+use crate::http::{RateLimiter, Throttled};
+use ahash::AHashMap;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use reqwest::{header::CONTENT_TYPE, Client, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fmt::{Debug, Display},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+/// 豆瓣的搜索建议接口
+const DOUBAN_SUGGEST: &str = "https://movie.douban.com/j/subject_suggest";
+
+/// 本地海报支持的扩展名
+const LOCAL_EXTS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "gif"];
+
+/// 海报信息
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PosterMeta {
+    pub title: String,
+    pub sub_title: Option<String>,
+    /// 上映年份
+    #[serde(default)]
+    pub year: Option<String>,
+    /// 图片链接，本地来源则是文件路径
+    pub url: String,
+}
+
+impl Display for PosterMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.sub_title {
+            None => write!(f, "Poster[{}", self.title)?,
+            Some(subtitle) => write!(f, "Poster[{}-{}", self.title, subtitle)?,
+        }
+        match &self.year {
+            None => write!(f, "]"),
+            Some(year) => write!(f, " {}]", year),
+        }
+    }
+}
+
+/// 下载的海报图片
+#[derive(Clone, Debug)]
+pub struct PosterImage {
+    pub bytes: Bytes,
+    /// 响应的 `Content-Type`，无法从内容判断图片格式时使用
+    pub content_type: Option<String>,
+}
+
+impl PosterImage {
+    /// 读取响应的内容及 `Content-Type`
+    async fn from_response(res: Response) -> Result<Self> {
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(Self {
+            bytes: res.bytes().await?,
+            content_type,
+        })
+    }
+}
+
+/// 海报来源
+#[async_trait]
+pub trait PosterSource: Send + Sync {
+    /// 来源的名字，用于日志
+    fn name(&self) -> &str;
+
+    /// 根据选项的名字查找海报
+    async fn lookup(&self, name: &str) -> Result<PosterMeta>;
+
+    /// 根据选项的名字查找所有可能的海报
+    ///
+    /// 默认只有 `lookup` 的结果
+    async fn search(&self, name: &str) -> Result<Vec<PosterMeta>> {
+        Ok(vec![self.lookup(name).await?])
+    }
+
+    /// 下载海报
+    async fn download(&self, meta: &PosterMeta) -> Result<PosterImage>;
+}
+
+/// 一次查找的结果，错误由等待的用户共享
+type Fetched = std::result::Result<Vec<(PosterMeta, PosterImage)>, Arc<anyhow::Error>>;
+
+/// 正在进行的查找，以选项的名字及海报数量区分
+type Inflight = AHashMap<(String, usize), Arc<OnceCell<Fetched>>>;
+
+/// 按顺序尝试的海报来源
+pub struct PosterChain {
+    sources: Vec<Box<dyn PosterSource>>,
+    cache: Option<PosterCache>,
+    inflight: Mutex<Inflight>,
+}
+
+impl PosterChain {
+    /// 根据配置创建，所有来源都使用 `http` 及 `proxy` 设置，共用 `ratelimit` 的频率限制，
+    /// 服务器要求等待的时间超过 `backoff` 中的 `max` 时不再等待
+    ///
+    /// 如果没有配置任何来源，则只使用豆瓣
+    pub fn from_config(
+        configs: &[PosterSourceConfig],
+        http: &HttpConfig,
+        proxy: &ProxyConfig,
+        ratelimit: &RateLimitConfig,
+        backoff: &BackoffConfig,
+    ) -> Result<Self> {
+        let client = poster_client(http, proxy)?;
+        let limiter = Arc::new(RateLimiter::new(ratelimit, backoff.max_delay()));
+        if configs.is_empty() {
+            return Ok(Self::new(vec![Box::new(DouBan::new(client, limiter))]));
+        }
+
+        let sources = configs
+            .iter()
+            .map(|c| -> Box<dyn PosterSource> {
+                match c {
+                    PosterSourceConfig::Douban => {
+                        Box::new(DouBan::new(client.clone(), limiter.clone()))
+                    }
+                    PosterSourceConfig::Local { dir } => Box::new(LocalDir::new(dir.into())),
+                    PosterSourceConfig::Json {
+                        url,
+                        query,
+                        img_field,
+                        title_field,
+                    } => Box::new(JsonApi {
+                        client: client.clone(),
+                        limiter: limiter.clone(),
+                        url: url.clone(),
+                        query: query.clone().unwrap_or_else(|| "q".into()),
+                        img_field: img_field.clone().unwrap_or_else(|| "img".into()),
+                        title_field: title_field.clone().unwrap_or_else(|| "title".into()),
+                    }),
+                }
+            })
+            .collect();
+
+        Ok(Self::new(sources))
+    }
+
+    fn new(sources: Vec<Box<dyn PosterSource>>) -> Self {
+        Self {
+            sources,
+            cache: None,
+            inflight: Mutex::default(),
+        }
+    }
+
+    /// 使用缓存
+    pub fn with_cache(mut self, cache: PosterCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// 依次尝试每个来源，返回第一个成功的来源中最相关的 `n` 张海报
+    ///
+    /// 其他用户正在查找同一个选项时，等待并使用其结果
+    pub async fn fetch(&self, name: &str, n: usize) -> Result<Vec<(PosterMeta, PosterImage)>> {
+        let key = (name.to_string(), n);
+        let cell = {
+            let mut inflight = self.inflight.lock().map_err(|_| anyhow!("无法获取锁"))?;
+            inflight.entry(key.clone()).or_default().clone()
+        };
+        let fetched = cell
+            .get_or_init(|| async {
+                let fetched = self.fetch_once(name, n).await.map_err(Arc::new);
+                if let Ok(mut inflight) = self.inflight.lock() {
+                    inflight.remove(&key);
+                }
+                fetched
+            })
+            .await;
+        fetched.clone().map_err(|e| SharedError(e).into())
+    }
+
+    /// 依次尝试每个来源
+    ///
+    /// 优先使用缓存，命中时不会请求任何来源
+    async fn fetch_once(&self, name: &str, n: usize) -> Result<Vec<(PosterMeta, PosterImage)>> {
+        if let Some(ref cache) = self.cache {
+            let cached = cache.get_metas(name).and_then(|metas| {
+                metas
+                    .into_iter()
+                    .take(n)
+                    .map(|meta| cache.get_poster(&meta.url).map(|b| (meta, b)))
+                    .collect::<Option<Vec<_>>>()
+            });
+            if let Some(posters) = cached.filter(|p| !p.is_empty()) {
+                log::debug!("缓存命中: {} {}张", name, posters.len());
+                return Ok(posters);
+            }
+        }
+
+        // 服务器要求稍后再试时保留原因，以便重试时等待
+        let mut throttled = None;
+        for source in self.sources.iter() {
+            let metas = match source.search(name).await {
+                Ok(metas) => rank_candidates(name, metas, n),
+                Err(e) => {
+                    log::debug!("{} 无法找到海报: {}, Err: {}", source.name(), name, e);
+                    throttled = throttled.or_else(|| Throttled::find(&e).cloned());
+                    continue;
+                }
+            };
+
+            let mut posters = vec![];
+            for meta in metas {
+                match self.download(source.as_ref(), &meta).await {
+                    Ok(img) => posters.push((meta, img)),
+                    Err(e) => {
+                        log::debug!("{} 无法下载海报: {}, Err: {}", source.name(), meta, e);
+                        throttled = throttled.or_else(|| Throttled::find(&e).cloned());
+                    }
+                }
+            }
+            if posters.is_empty() {
+                continue;
+            }
+
+            if let Some(ref cache) = self.cache {
+                let metas: Vec<_> = posters.iter().map(|(meta, _)| meta.clone()).collect();
+                if let Err(e) = cache.put_metas(name, &metas) {
+                    log::debug!("无法缓存海报信息: {}, Err: {}", name, e);
+                }
+            }
+            return Ok(posters);
+        }
+
+        match throttled {
+            Some(t) => Err(anyhow::Error::new(t).context("所有海报来源均失败了")),
+            None => Err(anyhow!("所有海报来源均失败了")),
+        }
+    }
+
+    /// 下载海报，优先使用缓存
+    async fn download(&self, source: &dyn PosterSource, meta: &PosterMeta) -> Result<PosterImage> {
+        let Some(ref cache) = self.cache else {
+            return source.download(meta).await;
+        };
+        if let Some(img) = cache.get_poster(&meta.url) {
+            return Ok(img);
+        }
+        let img = source.download(meta).await?;
+        if let Err(e) = cache.put_poster(&meta.url, &img) {
+            log::debug!("无法缓存海报: {}, Err: {}", meta, e);
+        }
+        Ok(img)
+    }
+}
+
+impl Default for PosterChain {
+    fn default() -> Self {
+        let client = poster_client(&HttpConfig::default(), &ProxyConfig::default())
+            .expect("无法创建海报来源的客户端");
+        Self::new(vec![Box::new(DouBan::new(client, Arc::default()))])
+    }
+}
+
+impl Debug for PosterChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.sources.iter().map(|s| s.name()))
+            .finish()
+    }
+}
+
+/// 共享的查找错误，保留错误链，以便判断是否需要稍后再试
+#[derive(Debug)]
+struct SharedError(Arc<anyhow::Error>);
+
+impl Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let e: &(dyn std::error::Error + Send + Sync + 'static) = self.0.as_ref().as_ref();
+        Some(e)
+    }
+}
+
+/// 海报来源共用的客户端
+///
+/// 与站点的客户端分开，不携带站点的cookie
+fn poster_client(http: &HttpConfig, proxy: &ProxyConfig) -> Result<Client> {
+    Ok(crate::http::builder(http, proxy)?.build()?)
+}
+
+/// 按与选项名字的相关程度排序，保留前 `n` 个
+///
+/// 选项名字带有年份时(比如 `沙丘 (2021)`)优先年份相同的，
+/// 其次是标题或原名相同的，相关程度相同时保持来源给出的顺序
+fn rank_candidates(name: &str, mut metas: Vec<PosterMeta>, n: usize) -> Vec<PosterMeta> {
+    let (title, year) = split_year(name);
+    let title = normalize_title(title);
+    let relevance = |meta: &PosterMeta| {
+        let mut r = 0;
+        if year.is_some() && meta.year.as_deref() == year {
+            r += 2;
+        }
+        let same_title = normalize_title(&meta.title) == title;
+        let same_sub_title = meta
+            .sub_title
+            .as_deref()
+            .map(|s| normalize_title(s) == title)
+            .unwrap_or_default();
+        if same_title || same_sub_title {
+            r += 1;
+        }
+        r
+    };
+
+    metas.sort_by_key(|meta| std::cmp::Reverse(relevance(meta)));
+    metas.truncate(n);
+    metas
+}
+
+/// 分离选项名字末尾的年份，比如 `沙丘 (2021)`、`沙丘（2021）`、`沙丘 2021`
+///
+/// 只有年份的名字(比如 `2046`)不会被分离
+fn split_year(name: &str) -> (&str, Option<&str>) {
+    let trimmed = name.trim_end().trim_end_matches([')', '）']);
+    let rest = trimmed.trim_end_matches(|c: char| c.is_ascii_digit());
+    if trimmed.len() - rest.len() != 4 {
+        return (name, None);
+    }
+    let year = &trimmed[rest.len()..];
+    let rest = rest.trim_end().trim_end_matches(['(', '（']).trim_end();
+    if rest.is_empty() {
+        return (name, None);
+    }
+    (rest, Some(year))
+}
+
+#[derive(Deserialize)]
+struct DouBanData {
+    /// 图片链接
+    img: String,
+    title: String,
+    sub_title: Option<String>,
+    year: Option<String>,
+}
+
+impl From<DouBanData> for PosterMeta {
+    fn from(value: DouBanData) -> Self {
+        Self {
+            title: value.title,
+            sub_title: value.sub_title,
+            year: value.year.filter(|y| !y.is_empty()),
+            url: value.img,
+        }
+    }
+}
+
+/// 豆瓣
+pub struct DouBan {
+    client: Client,
+    limiter: Arc<RateLimiter>,
+}
+
+impl DouBan {
+    pub fn new(client: Client, limiter: Arc<RateLimiter>) -> Self {
+        Self { client, limiter }
+    }
+
+    /// 所有搜索建议
+    async fn suggest(&self, name: &str) -> Result<Vec<DouBanData>> {
+        let req = self.client.get(DOUBAN_SUGGEST).query(&[("q", name)]);
+        let res: Vec<DouBanData> = self.limiter.send(req).await?.json().await?;
+
+        if res.is_empty() {
+            Err(anyhow!("无法获取豆瓣数据"))
+        } else {
+            Ok(res)
+        }
+    }
+}
+
+#[async_trait]
+impl PosterSource for DouBan {
+    fn name(&self) -> &str {
+        "douban"
+    }
+
+    async fn lookup(&self, name: &str) -> Result<PosterMeta> {
+        let res = self.suggest(name).await?;
+        if let Some(d) = res.into_iter().next() {
+            Ok(d.into())
+        } else {
+            Err(anyhow!("无法获取豆瓣数据"))
+        }
+    }
+
+    async fn search(&self, name: &str) -> Result<Vec<PosterMeta>> {
+        let res = self.suggest(name).await?;
+        Ok(res.into_iter().map(PosterMeta::from).collect())
+    }
+
+    async fn download(&self, meta: &PosterMeta) -> Result<PosterImage> {
+        let req = self.client.get(&meta.url);
+        PosterImage::from_response(self.limiter.send(req).await?).await
+    }
+}
+
+/// 本地文件夹
+///
+/// 海报文件以选项的名字命名，比如 `肖申克的救赎.jpg`
+pub struct LocalDir {
+    dir: PathBuf,
+}
+
+impl LocalDir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+#[async_trait]
+impl PosterSource for LocalDir {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn lookup(&self, name: &str) -> Result<PosterMeta> {
+        // 选项的名字来自站点，不能访问文件夹以外的文件
+        if !is_file_name(name) {
+            return Err(anyhow!("不能作为文件名: {}", name));
+        }
+        let Some(path) = LOCAL_EXTS
+            .iter()
+            .map(|ext| self.dir.join(format!("{}.{}", name, ext)))
+            .find(|p| p.is_file())
+        else {
+            return Err(anyhow!("本地不存在: {}", self.dir.display()));
+        };
+
+        Ok(PosterMeta {
+            title: name.into(),
+            sub_title: None,
+            year: None,
+            url: path.display().to_string(),
+        })
+    }
+
+    async fn download(&self, meta: &PosterMeta) -> Result<PosterImage> {
+        // 本地文件根据扩展名判断
+        let content_type = image::ImageFormat::from_path(&meta.url)
+            .ok()
+            .map(|f| f.to_mime_type().to_string());
+        Ok(PosterImage {
+            bytes: tokio::fs::read(&meta.url).await?.into(),
+            content_type,
+        })
+    }
+}
+
+/// 是否是单个文件名，不包含路径分隔符，也不是 `.` 或 `..`
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains(['/', '\\', '\0'])
+}
+
+/// 通用的 JSON 接口
+///
+/// 请求 `{url}?{query}={name}`，返回一个对象或者对象数组
+pub struct JsonApi {
+    client: Client,
+    limiter: Arc<RateLimiter>,
+    url: String,
+    query: String,
+    img_field: String,
+    title_field: String,
+}
+
+impl JsonApi {
+    /// 返回的所有对象
+    async fn items(&self, name: &str) -> Result<Vec<Value>> {
+        let req = self.client.get(&self.url).query(&[(self.query.as_str(), name)]);
+        let res: Value = self.limiter.send(req).await?.json().await?;
+
+        match res {
+            Value::Array(v) if !v.is_empty() => Ok(v),
+            v @ Value::Object(_) => Ok(vec![v]),
+            _ => Err(anyhow!("无法解析返回的数据")),
+        }
+    }
+
+    fn to_meta(&self, item: &Value, name: &str) -> Result<PosterMeta> {
+        let Some(url) = item.get(&self.img_field).and_then(Value::as_str) else {
+            return Err(anyhow!("返回的数据中没有: {}", self.img_field));
+        };
+        let title = item
+            .get(&self.title_field)
+            .and_then(Value::as_str)
+            .unwrap_or(name);
+
+        Ok(PosterMeta {
+            title: title.into(),
+            sub_title: None,
+            year: None,
+            url: url.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl PosterSource for JsonApi {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    async fn lookup(&self, name: &str) -> Result<PosterMeta> {
+        let items = self.items(name).await?;
+        self.to_meta(&items[0], name)
+    }
+
+    async fn search(&self, name: &str) -> Result<Vec<PosterMeta>> {
+        let items = self.items(name).await?;
+        let metas: Vec<_> = items
+            .iter()
+            .filter_map(|item| self.to_meta(item, name).ok())
+            .collect();
+        if metas.is_empty() {
+            return Err(anyhow!("返回的数据中没有: {}", self.img_field));
+        }
+        Ok(metas)
+    }
+
+    async fn download(&self, meta: &PosterMeta) -> Result<PosterImage> {
+        let req = self.client.get(&meta.url);
+        PosterImage::from_response(self.limiter.send(req).await?).await
+    }
+}
+
+#[cfg(test)]
+mod poster_test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn meta(title: &str, sub_title: Option<&str>, year: &str) -> PosterMeta {
+        PosterMeta {
+            title: title.into(),
+            sub_title: sub_title.map(String::from),
+            year: Some(year.into()),
+            url: format!("{}-{}", title, year),
+        }
+    }
+
+    #[test]
+    fn split_year_test() {
+        assert_eq!(split_year("沙丘 (2021)"), ("沙丘", Some("2021")));
+        assert_eq!(split_year("沙丘（2021）"), ("沙丘", Some("2021")));
+        assert_eq!(split_year("Dune 2021"), ("Dune", Some("2021")));
+        assert_eq!(split_year("2046"), ("2046", None));
+        assert_eq!(split_year("沙丘"), ("沙丘", None));
+    }
+
+    #[tokio::test]
+    async fn local_test() {
+        assert!(is_file_name("沙丘 (2021)"));
+        for name in ["", ".", "..", "../secret", "a/b", "/etc/passwd", "a\\b"] {
+            assert!(!is_file_name(name), "{}", name);
+        }
+
+        let dir = std::env::temp_dir().join(format!("tjuptatt_local_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("posters")).unwrap();
+        std::fs::write(dir.join("secret.jpg"), b"secret").unwrap();
+        std::fs::write(dir.join("posters").join("沙丘.jpg"), b"dune").unwrap();
+
+        let local = LocalDir::new(dir.join("posters"));
+        let meta = local.lookup("沙丘").await.unwrap();
+        let img = local.download(&meta).await.unwrap();
+        assert_eq!(&img.bytes[..], b"dune");
+        assert_eq!(img.content_type.as_deref(), Some("image/jpeg"));
+        assert!(local.lookup("../secret").await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rank_test() {
+        let metas = vec![
+            meta("沙丘2", None, "2024"),
+            meta("沙丘", Some("Dune"), "1984"),
+            meta("沙丘", Some("Dune"), "2021"),
+        ];
+        let ranked = rank_candidates("沙丘 (2021)", metas.clone(), 2);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].url, "沙丘-2021");
+        assert_eq!(ranked[1].url, "沙丘-1984");
+
+        // 原名相同也算相关
+        let ranked = rank_candidates("Dune", metas, 3);
+        assert_eq!(ranked[0].url, "沙丘-1984");
+        assert_eq!(ranked[2].url, "沙丘2-2024");
+    }
+
+    /// 记录查找次数的来源
+    struct Counting(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl PosterSource for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn lookup(&self, name: &str) -> Result<PosterMeta> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Ok(meta(name, None, "2021"))
+        }
+
+        async fn download(&self, _meta: &PosterMeta) -> Result<PosterImage> {
+            Ok(PosterImage {
+                bytes: Bytes::new(),
+                content_type: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn inflight_test() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let chain = PosterChain::new(vec![Box::new(Counting(count.clone()))]);
+        let (a, b) = tokio::join!(chain.fetch("沙丘", 1), chain.fetch("沙丘", 1));
+        assert_eq!(a.unwrap()[0].0.url, b.unwrap()[0].0.url);
+        chain.fetch("沙丘", 1).await.unwrap();
+
+        // 同时进行的查找只请求一次，完成后再查找会重新请求
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(chain.inflight.lock().unwrap().is_empty());
+    }
+
+    /// 总是要求稍后再试的来源
+    struct Busy;
+
+    #[async_trait]
+    impl PosterSource for Busy {
+        fn name(&self) -> &str {
+            "busy"
+        }
+
+        async fn lookup(&self, _name: &str) -> Result<PosterMeta> {
+            let retry_after = Some(std::time::Duration::from_secs(30));
+            let status = reqwest::StatusCode::TOO_MANY_REQUESTS;
+            Err(Throttled { status, retry_after }.into())
+        }
+
+        async fn download(&self, _meta: &PosterMeta) -> Result<PosterImage> {
+            Ok(PosterImage {
+                bytes: Bytes::new(),
+                content_type: None,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn throttled_test() {
+        let chain = PosterChain::new(vec![Box::new(Busy)]);
+        let (a, b) = tokio::join!(chain.fetch("沙丘", 1), chain.fetch("沙丘", 1));
+        for e in [a.unwrap_err(), b.unwrap_err()] {
+            let throttled = Throttled::find(&e).unwrap();
+            assert_eq!(throttled.retry_after.unwrap().as_secs(), 30);
+        }
+    }
+}
@@ -0,0 +1,878 @@
+//! 站点
+//!
+//! 所有请求的链接都由 `[site]` 中的 `base_url` 及各个页面的路径得到，
+//! 方便使用镜像域名或者本地的模拟服务器
+//!
+//! - `tjupt`: 北洋园PT，需要根据海报回答问题，漏签时可以补签
+//! - `nexusphp`: 访问签到页面即可完成签到的 NexusPHP 站点
+
+use crate::{
+    config::{SiteConfig, SiteKind, UserConfig},
+    http::{Session, SessionRequest},
+    login::{LoginError, LoginFailure, LoginForm},
+};
+use ahash::AHashMap;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use reqwest::{StatusCode, Url};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+};
+
+/// 默认的站点
+pub const DEFAULT_BASE_URL: &str = "https://tjupt.org/";
+
+/// 页面上出现这些文字说明今天已经签到了
+const ATTENDED_MARKERS: [&str; 3] = ["已签到", "已经签到", "签到成功"];
+
+lazy_static! {
+    // //input[@type="radio"]
+    static ref INPUT_RADIO_SELE: Selector = Selector::parse(r#"input[type="radio"]"#).unwrap();
+    static ref TD: Selector = Selector::parse(r#"td[id="outer"]"#).unwrap();
+    static ref IMG: Selector = Selector::parse("img").unwrap();
+    static ref FORM: Selector = Selector::parse("form").unwrap();
+    static ref FIELD: Selector = Selector::parse("input[name], button[name]").unwrap();
+    static ref INFO_BLOCK: Selector = Selector::parse("#info_block").unwrap();
+    static ref USER_LINK: Selector = Selector::parse(r#"a[href*="userdetails.php"]"#).unwrap();
+    static ref ROW: Selector = Selector::parse("tr").unwrap();
+    static ref CELL: Selector = Selector::parse("td").unwrap();
+}
+
+/// 签到页面上的题目
+pub struct Challenge {
+    /// 选项们 `(name, value)`
+    pub answers: Vec<(String, String)>,
+    /// 题图的链接
+    pub img_url: String,
+}
+
+/// 补签的表单
+#[derive(Debug, Clone)]
+pub struct MakeUp {
+    /// 表单提交的链接
+    pub action: Url,
+    /// 是否使用 post 提交
+    pub post: bool,
+    /// 表单中的字段 `(name, value)`
+    pub fields: Vec<(String, String)>,
+    /// 补签一次需要的魔力值，无法获取时是 `None`
+    pub cost: Option<f64>,
+    /// 当前的魔力值
+    pub bonus: Option<f64>,
+}
+
+/// 签到的奖励及统计，从签到结果页面中解析，页面上没有的是 `None`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Reward {
+    /// 获得的魔力值
+    pub bonus: Option<f64>,
+    /// 连续签到的天数
+    pub streak: Option<u32>,
+    /// 今天的签到排名
+    pub rank: Option<u32>,
+    /// 累计签到的天数
+    pub total: Option<u32>,
+}
+
+impl Reward {
+    /// 解析 NexusPHP 风格的签到结果，比如
+    /// `这是您的第 123 次签到，已连续签到 5 天，今日签到排名：第 3 位，本次签到获得 30 个魔力值`
+    pub fn parse(html: &str) -> Self {
+        let text: String = Html::parse_document(html).root_element().text().collect();
+        let count = |n: f64| n as u32;
+        Self {
+            bonus: number_after(&text, "获得"),
+            streak: number_after(&text, "连续签到").map(count),
+            rank: number_after(&text, "排名")
+                .or_else(|| number_before(&text, "个签到"))
+                .map(count),
+            total: number_before(&text, "次签到")
+                .or_else(|| number_after(&text, "累计签到"))
+                .map(count),
+        }
+    }
+
+    /// 什么都没有解析到
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Display for Reward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut items = vec![];
+        if let Some(b) = self.bonus {
+            items.push(format!("获得 {} 魔力值", b));
+        }
+        if let Some(s) = self.streak {
+            items.push(format!("连续签到 {} 天", s));
+        }
+        if let Some(r) = self.rank {
+            items.push(format!("今日第 {} 名", r));
+        }
+        if let Some(t) = self.total {
+            items.push(format!("累计签到 {} 天", t));
+        }
+        write!(f, "{}", items.join("，"))
+    }
+}
+
+/// 账号信息，页面上没有的是 `None`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// 用户名
+    pub name: Option<String>,
+    /// 上传量，保留页面上的单位，比如 `1.23 TB`
+    pub uploaded: Option<String>,
+    /// 下载量
+    pub downloaded: Option<String>,
+    /// 分享率，没有下载时是 `None`
+    pub ratio: Option<f64>,
+    /// 魔力值
+    pub bonus: Option<f64>,
+    /// 用户等级
+    pub class: Option<String>,
+    /// 邀请名额
+    pub invites: Option<u32>,
+    /// 未读的短消息
+    pub unread: Option<u32>,
+}
+
+impl Profile {
+    /// 解析页面顶部的用户信息栏
+    ///
+    /// 返回信息及用户详情页面的链接
+    fn parse_info_block(html: &str) -> (Self, Option<String>) {
+        let doc = Html::parse_document(html);
+        let block = doc.select(&INFO_BLOCK).next().unwrap_or(doc.root_element());
+        let text: String = block.text().collect();
+        let user = block.select(&USER_LINK).next();
+
+        let profile = Self {
+            name: user
+                .map(|a| a.text().collect::<String>().trim().to_string())
+                .filter(|n| !n.is_empty()),
+            ..Self::parse_text(&text)
+        };
+        let link = user.and_then(|a| a.value().attr("href")).map(String::from);
+        (profile, link)
+    }
+
+    /// 从用户详情页面补充缺少的信息
+    fn merge_details(&mut self, html: &str) {
+        let doc = Html::parse_document(html);
+        let text: String = doc.root_element().text().collect();
+        let details = Self::parse_text(&text);
+
+        // 等级通常是图片，名字在 `title` 或者 `alt` 中
+        let class = doc.select(&ROW).find_map(|row| {
+            let mut cells = row.select(&CELL);
+            let head = cells.next()?;
+            if head.text().collect::<String>().trim() != "等级" {
+                return None;
+            }
+            let cell = cells.next()?;
+            let name = match cell.select(&IMG).next() {
+                Some(img) => img.value().attr("title").or(img.value().attr("alt"))?.to_string(),
+                None => cell.text().collect::<String>().trim().to_string(),
+            };
+            (!name.is_empty()).then_some(name)
+        });
+
+        self.uploaded = self.uploaded.take().or(details.uploaded);
+        self.downloaded = self.downloaded.take().or(details.downloaded);
+        self.ratio = self.ratio.or(details.ratio);
+        self.bonus = self.bonus.or(details.bonus);
+        self.class = self.class.take().or(class);
+        self.invites = self.invites.or(details.invites);
+        self.unread = self.unread.or(details.unread);
+    }
+
+    /// 根据文字解析，比如 `分享率: 2.345 上传量: 1.23 TB 下载量: 0.53 TB`
+    fn parse_text(text: &str) -> Self {
+        let count = |n: f64| n as u32;
+        Self {
+            name: None,
+            uploaded: size_after(text, "上传量"),
+            downloaded: size_after(text, "下载量"),
+            ratio: number_after(text, "分享率"),
+            bonus: number_after(text, "魔力值"),
+            class: None,
+            invites: number_after(text, "邀请").map(count),
+            // 收件箱后面是 `3 (1 新)`
+            unread: number_before(text, "新)").map(count),
+        }
+    }
+}
+
+/// 签到页面的状态
+pub enum PageState {
+    /// 需要回答问题
+    Challenge(Challenge),
+    /// 今天已经签到了
+    Attended,
+    /// 漏签了，可以补签
+    MakeUp(MakeUp),
+    /// 访问签到页面就已经完成了签到，页面上的奖励
+    Done(Reward),
+}
+
+/// 站点
+///
+/// 登陆及获取签到页面默认使用 NexusPHP 的方式
+#[async_trait]
+pub trait Site: Send + Sync + Debug {
+    /// 站点的名字，用于日志
+    fn name(&self) -> &str;
+
+    /// 站点的各个链接
+    fn urls(&self) -> &SiteUrls;
+
+    /// 在不加载cookie的情况下登陆
+    ///
+    /// 登陆表单中有验证码时一起填写，两步验证码也可能在 takelogin 之后的单独页面中
+    ///
+    /// 返回登陆后跳转到的 `returnto` 页面的String，
+    /// 失败时的错误是 [`LoginError`]，可以通过 `downcast_ref` 得到原因
+    async fn login(&self, client: &Session, user: &UserConfig, returnto: &Url) -> Result<String> {
+        let urls = self.urls();
+        let (_, page, html) = read_page(client.get(urls.login().clone())).await?;
+        let form = LoginForm::parse(&html);
+        let mut fields = form.hidden.clone();
+        fields.extend(
+            [
+                ("username", user.id()),
+                ("password", user.pwd()),
+                ("logout", "7days"),
+                ("returnto", returnto.path()),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        fields.extend(form.answer(client, user, &page).await?);
+        let post = client.post(urls.takelogin().clone()).form(&fields);
+        let (mut status, mut landed, mut body) = read_page(post).await?;
+        let mut form_paths = vec![urls.takelogin().path().to_string()];
+        let failed_at = |landed: &Url, form_paths: &[String]| {
+            form_paths.iter().any(|p| p == landed.path()) || urls.is_login(landed)
+        };
+
+        // 单独的两步验证页面没有密码输入框，被退回登陆页面时重新提交只会多一次失败的登陆
+        let second = LoginForm::parse(&body);
+        let second_step = second.totp.is_some() && !second.password;
+        if failed_at(&landed, &form_paths) {
+            let classified = LoginError::classify(&body)
+                .filter(|e| !(second_step && e.failure == LoginFailure::TwoFactor));
+            if let Some(e) = classified {
+                return Err(e.into());
+            }
+        }
+        if second_step && landed.path() != returnto.path() {
+            let mut fields = second.hidden.clone();
+            fields.extend(second.answer(client, user, &landed).await?);
+            let action = landed
+                .join(second.action.as_deref().unwrap_or_default())
+                .map_err(|e| LoginError::new(LoginFailure::TwoFactor, e.to_string()))?;
+            form_paths.push(action.path().to_string());
+            (status, landed, body) = read_page(client.post(action).form(&fields)).await?;
+        }
+
+        // 成功时会被重定向到 `returnto`，失败时停留在提交的页面并说明原因
+        if failed_at(&landed, &form_paths) {
+            if let Some(e) = LoginError::classify(&body) {
+                return Err(e.into());
+            }
+        }
+        if !status.is_success() {
+            let e = LoginError::new(LoginFailure::Transport, format!("takelogin 返回 {}", status));
+            return Err(e.into());
+        }
+
+        let (_, page, html) = read_page(client.get(returnto.clone())).await?;
+        if urls.is_login(&page) {
+            let e = LoginError::new(LoginFailure::Unknown, "登陆后仍然被重定向到登陆页面");
+            return Err(e.into());
+        }
+        Ok(html)
+    }
+
+    /// 检查登陆状态时访问的页面
+    ///
+    /// 访问它不能完成签到，默认是签到页面
+    fn status_url(&self) -> &Url {
+        self.urls().attendance()
+    }
+
+    /// 根据 `status_url` 的页面判断今天是否已经签到，无法判断时返回 `None`
+    fn attended(&self, html: &str) -> Option<bool>;
+
+    /// 补签，完成后需要重新获取签到页面
+    async fn make_up(&self, _client: &Session, _form: &MakeUp) -> Result<()> {
+        Err(anyhow!("{} 不支持补签", self.name()))
+    }
+
+    /// cookie是否有效，不会登陆
+    async fn logged_in(&self, client: &Session) -> Result<bool> {
+        let req = client.get(self.status_url().clone()).send().await?;
+        Ok(!self.urls().is_login(req.url()))
+    }
+
+    /// 获取签到页面
+    ///
+    /// 如果被重定向到登陆页面，说明cookie失效了，需要登陆
+    async fn attendance_page(&self, client: &Session, user: &UserConfig) -> Result<String> {
+        let req = client.get(self.urls().attendance().clone()).send().await?;
+        if self.urls().is_login(req.url()) {
+            self.login(client, user, self.urls().attendance()).await
+        } else {
+            let content = req.text().await?;
+            Ok(content)
+        }
+    }
+
+    /// 解析签到页面的状态
+    fn page_state(&self, html: &str) -> Result<PageState>;
+
+    /// 账号信息
+    ///
+    /// 解析首页的用户信息栏及用户详情页面，cookie失效时会登陆，但是不会访问签到页面
+    async fn profile(&self, client: &Session, user: &UserConfig) -> Result<Profile> {
+        let urls = self.urls();
+        let req = client.get(urls.base().clone()).send().await?;
+        let html = if urls.is_login(req.url()) {
+            self.login(client, user, urls.base()).await?
+        } else {
+            req.text().await?
+        };
+
+        let (mut profile, details) = Profile::parse_info_block(&html);
+        if let Some(link) = details {
+            let html = client.get(urls.base().join(&link)?).send().await?.text().await?;
+            profile.merge_details(&html);
+        }
+        Ok(profile)
+    }
+
+    /// 提交答案，返回结果页面上的奖励
+    async fn submit(&self, client: &Session, answer: &str) -> Result<Reward>;
+}
+
+/// 根据配置创建
+pub fn from_config(config: &SiteConfig) -> Result<Arc<dyn Site>> {
+    let urls = SiteUrls::from_config(config)?;
+    let name = config.name().to_string();
+    Ok(match config.kind() {
+        SiteKind::Tjupt => Arc::new(TjuPt { name, urls }),
+        SiteKind::Nexusphp => Arc::new(NexusPhp { name, urls }),
+    })
+}
+
+/// 所有站点，用户通过名字选择
+pub struct Sites {
+    default: Arc<dyn Site>,
+    named: AHashMap<String, Arc<dyn Site>>,
+}
+
+impl Sites {
+    /// `default` 是 `[site]`，`others` 是 `[[sites]]`
+    pub fn from_config(default: &SiteConfig, others: &[SiteConfig]) -> Result<Self> {
+        let default = from_config(default)?;
+        let mut named = AHashMap::new();
+        let _r = named.insert(default.name().to_string(), default.clone());
+        for config in others.iter() {
+            let _r = named.insert(config.name().to_string(), from_config(config)?);
+        }
+        Ok(Self { default, named })
+    }
+
+    /// 根据名字查找，`None` 是默认站点
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<dyn Site>> {
+        match name {
+            None => Ok(self.default.clone()),
+            Some(name) => self
+                .named
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow!("站点不存在: {}", name)),
+        }
+    }
+}
+
+impl Default for Sites {
+    fn default() -> Self {
+        Self::from_config(&SiteConfig::default(), &[]).expect("默认的站点设置无效")
+    }
+}
+
+/// 北洋园PT
+///
+/// 签到页面上有一张海报及若干电影名字，选出海报对应的电影
+#[derive(Debug)]
+pub struct TjuPt {
+    name: String,
+    urls: SiteUrls,
+}
+
+#[async_trait]
+impl Site for TjuPt {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn urls(&self) -> &SiteUrls {
+        &self.urls
+    }
+
+    fn page_state(&self, html: &str) -> Result<PageState> {
+        let page = parse_att_page(html, &self.urls)?;
+        if !page.answers.is_empty() {
+            let Some(img) = page.img else {
+                return Err(anyhow!("无法定位图片"));
+            };
+            return Ok(PageState::Challenge(Challenge {
+                answers: page.answers,
+                img_url: self.urls.resolve(&img)?.to_string(),
+            }));
+        }
+        // 今天已经签到的页面上也可能有补签以前的表单
+        if ATTENDED_MARKERS.iter().any(|m| page.text.contains(m)) {
+            return Ok(PageState::Attended);
+        }
+        if let Some(form) = page.makeup {
+            return Ok(PageState::MakeUp(form));
+        }
+        Err(anyhow!("无法识别签到页面: 没有选项，也没有补签"))
+    }
+
+    fn attended(&self, html: &str) -> Option<bool> {
+        match self.page_state(html).ok()? {
+            PageState::Attended | PageState::Done(_) => Some(true),
+            PageState::Challenge(_) | PageState::MakeUp(_) => Some(false),
+        }
+    }
+
+    async fn make_up(&self, client: &Session, form: &MakeUp) -> Result<()> {
+        let req = if form.post {
+            client.post(form.action.clone()).form(&form.fields)
+        } else {
+            client.get(form.action.clone()).query(&form.fields)
+        };
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("补签失败: {}", res.status()));
+        }
+        if res.text().await?.contains("补签成功") {
+            Ok(())
+        } else {
+            Err(anyhow!("补签失败"))
+        }
+    }
+
+    async fn submit(&self, client: &Session, answer: &str) -> Result<Reward> {
+        let data = &[("answer", answer), ("submit", "提交")];
+        let r = client
+            .post(self.urls.attendance().clone())
+            .form(data)
+            .send()
+            .await?
+            .text()
+            .await?;
+        if r.contains("签到成功") {
+            Ok(Reward::parse(&r))
+        } else {
+            Err(anyhow!("签到失败"))
+        }
+    }
+}
+
+/// 访问签到页面即可完成签到的 NexusPHP 站点
+#[derive(Debug)]
+pub struct NexusPhp {
+    name: String,
+    urls: SiteUrls,
+}
+
+#[async_trait]
+impl Site for NexusPhp {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn urls(&self) -> &SiteUrls {
+        &self.urls
+    }
+
+    /// 访问签到页面就会签到，所以检查首页
+    fn status_url(&self) -> &Url {
+        self.urls.base()
+    }
+
+    fn attended(&self, _html: &str) -> Option<bool> {
+        None
+    }
+
+    fn page_state(&self, html: &str) -> Result<PageState> {
+        Ok(PageState::Done(Reward::parse(html)))
+    }
+
+    async fn submit(&self, _client: &Session, _answer: &str) -> Result<Reward> {
+        Err(anyhow!("{} 没有需要回答的题目", self.name))
+    }
+}
+
+/// 站点的各个链接
+#[derive(Debug, Clone)]
+pub struct SiteUrls {
+    base: Url,
+    /// 登陆页面
+    login: Url,
+    /// post的登陆url
+    takelogin: Url,
+    /// 签到页面
+    attendance: Url,
+}
+
+impl SiteUrls {
+    /// 根据配置创建
+    pub fn from_config(config: &SiteConfig) -> Result<Self> {
+        let mut base = config.base_url().to_string();
+        // 没有 `/` 结尾时 `join` 会替换掉最后一段路径
+        if !base.ends_with('/') {
+            base.push('/');
+        }
+        let base =
+            Url::parse(&base).map_err(|e| anyhow!("base_url 无效: {}, Err: {}", base, e))?;
+        if !matches!(base.scheme(), "http" | "https") {
+            return Err(anyhow!("base_url 只支持 http 或 https: {}", base));
+        }
+
+        let join = |name: &str, path: &str| {
+            base.join(path)
+                .map_err(|e| anyhow!("{} 无效: {}, Err: {}", name, path, e))
+        };
+        Ok(Self {
+            login: join("login", config.login())?,
+            takelogin: join("takelogin", config.takelogin())?,
+            attendance: join("attendance", config.attendance())?,
+            base,
+        })
+    }
+
+    pub fn base(&self) -> &Url {
+        &self.base
+    }
+
+    pub fn login(&self) -> &Url {
+        &self.login
+    }
+
+    pub fn takelogin(&self) -> &Url {
+        &self.takelogin
+    }
+
+    pub fn attendance(&self) -> &Url {
+        &self.attendance
+    }
+
+    /// 是否被重定向到了登陆页面
+    pub fn is_login(&self, url: &Url) -> bool {
+        url.path() == self.login.path()
+    }
+
+    /// 解析页面中的链接，比如题图的 `src`
+    ///
+    /// 可以是绝对路径、相对路径或者完整的链接
+    pub fn resolve(&self, link: &str) -> Result<Url> {
+        self.attendance
+            .join(link)
+            .map_err(|e| anyhow!("无法解析链接: {}, Err: {}", link, e))
+    }
+}
+
+impl Default for SiteUrls {
+    fn default() -> Self {
+        Self::from_config(&SiteConfig::default()).expect("默认的站点链接无效")
+    }
+}
+
+/// 解析后的北洋园PT签到页面
+struct AttPage {
+    /// 选项们 `(name, value)`
+    answers: Vec<(String, String)>,
+    /// 题图的链接
+    img: Option<String>,
+    makeup: Option<MakeUp>,
+    /// 页面的文字
+    text: String,
+}
+
+/// 解析北洋园PT的签到页面
+fn parse_att_page(html: &str, urls: &SiteUrls) -> Result<AttPage> {
+    let doc = Html::parse_document(html);
+
+    // //input[@type="radio"]s
+    let radio = doc.select(&INPUT_RADIO_SELE);
+
+    // 选项们
+    let answers = radio
+        .into_iter()
+        .filter_map(|e| {
+            let name = e.next_sibling()?;
+            let name = name.value().as_text()?;
+            let value = e.value().attr("value")?;
+
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    // 图片
+    let img = doc
+        .select(&TD)
+        .next()
+        .and_then(|e| e.select(&IMG).next())
+        .and_then(|e| e.value().attr("src"))
+        .map(String::from);
+
+    let text: String = doc.root_element().text().collect();
+
+    // 补签的表单，文字或者按钮中有 `补签`
+    let makeup = doc
+        .select(&FORM)
+        .find(|form| {
+            form.text().any(|t| t.contains("补签"))
+                || form
+                    .select(&FIELD)
+                    .any(|e| e.value().attr("value").unwrap_or_default().contains("补签"))
+        })
+        .map(|form| -> Result<MakeUp> {
+            let action = urls.resolve(form.value().attr("action").unwrap_or_default())?;
+            let post = form
+                .value()
+                .attr("method")
+                .map(|m| m.eq_ignore_ascii_case("post"))
+                .unwrap_or_default();
+            let fields = form
+                .select(&FIELD)
+                .filter_map(|e| {
+                    let name = e.value().attr("name")?;
+                    Some((name.into(), e.value().attr("value").unwrap_or_default().into()))
+                })
+                .collect();
+            let form_text: String = form.text().collect();
+            Ok(MakeUp {
+                action,
+                post,
+                fields,
+                // 只相信表单中的花费，页面其他地方的数字可能是签到奖励等
+                cost: number_before(&form_text, "魔力"),
+                bonus: number_after(&text, "魔力值"),
+            })
+        })
+        .transpose()?;
+
+    Ok(AttPage {
+        answers,
+        img,
+        makeup,
+        text,
+    })
+}
+
+/// 发送请求，返回状态码、最终的链接及页面，网络错误时是 [`LoginFailure::Transport`]
+async fn read_page(req: SessionRequest<'_>) -> Result<(StatusCode, Url, String), LoginError> {
+    let res = req.send().await?;
+    let status = res.status();
+    let url = res.url().clone();
+    Ok((status, url, res.text().await?))
+}
+
+/// `marker` 前面紧挨着的数字，比如 `需要 500 魔力` 中的 `500`
+fn number_before(text: &str, marker: &str) -> Option<f64> {
+    text.match_indices(marker).find_map(|(i, _)| {
+        let before = text[..i].trim_end();
+        let start = before
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.' || c == ',')
+            .len();
+        parse_number(&before[start..])
+    })
+}
+
+/// `marker` 后面的第一个数字，比如 `魔力值 [使用]: 12,345.6` 中的 `12345.6`
+fn number_after(text: &str, marker: &str) -> Option<f64> {
+    text.match_indices(marker).find_map(|(i, _)| {
+        let after = &text[i + marker.len()..];
+        // 只在标记附近查找
+        let start = after
+            .char_indices()
+            .take(16)
+            .find(|(_, c)| c.is_ascii_digit())?
+            .0;
+        let after = &after[start..];
+        let end = after
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+            .unwrap_or(after.len());
+        parse_number(&after[..end])
+    })
+}
+
+/// `marker` 后面的数据量，比如 `上传量: 1.23 TB` 中的 `1.23 TB`
+fn size_after(text: &str, marker: &str) -> Option<String> {
+    let (i, _) = text.match_indices(marker).next()?;
+    let after = &text[i + marker.len()..];
+    let start = after
+        .char_indices()
+        .take(16)
+        .find(|(_, c)| c.is_ascii_digit())?
+        .0;
+    let mut words = after[start..].split_whitespace();
+    let number = words.next()?;
+    // 单位可能和数字连在一起
+    if number.ends_with('B') {
+        return Some(number.into());
+    }
+    match words.next() {
+        Some(unit) if unit.ends_with('B') && unit.len() <= 3 => {
+            Some(format!("{} {}", number, unit))
+        }
+        _ => Some(number.into()),
+    }
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    s.replace(',', "").trim_end_matches('.').parse().ok()
+}
+
+#[cfg(test)]
+mod site_test {
+    use super::*;
+
+    #[test]
+    fn resolve_test() {
+        let site = SiteUrls::default();
+        assert_eq!(site.login().as_str(), "https://tjupt.org/login.php");
+        assert_eq!(
+            site.resolve("/pic/a.jpg").unwrap().as_str(),
+            "https://tjupt.org/pic/a.jpg"
+        );
+
+        let config: SiteConfig = toml::from_str(
+            r#"
+            base_url = "http://[::1]:8080/pt"
+            attendance = "sign.php"
+            "#,
+        )
+        .unwrap();
+        let site = SiteUrls::from_config(&config).unwrap();
+        assert_eq!(site.attendance().as_str(), "http://[::1]:8080/pt/sign.php");
+        assert_eq!(
+            site.resolve("img.php?id=1").unwrap().as_str(),
+            "http://[::1]:8080/pt/img.php?id=1"
+        );
+        assert!(site.is_login(&site.login().join("?returnto=x").unwrap()));
+    }
+
+    #[test]
+    fn page_state_test() {
+        let site = TjuPt {
+            name: "tjupt".into(),
+            urls: SiteUrls::default(),
+        };
+        let html = r#"<html><body>
+            <span>魔力值 [使用]: 12,345.6</span>
+            <form method="post" action="attendance.php?action=makeup">
+                <p>您已经漏签 2 天，补签一次需要 500 魔力值</p>
+                <input type="hidden" name="days" value="2">
+                <input type="submit" name="makeup" value="补签">
+            </form>
+        </body></html>"#;
+        let PageState::MakeUp(form) = site.page_state(html).unwrap() else {
+            panic!("应该可以补签");
+        };
+        assert!(form.post);
+        assert_eq!(form.cost, Some(500.0));
+        assert_eq!(form.bonus, Some(12345.6));
+        assert_eq!(form.fields.len(), 2);
+        assert_eq!(
+            form.action.as_str(),
+            "https://tjupt.org/attendance.php?action=makeup"
+        );
+
+        // 规则中提到了魔力，但是表单中没有花费
+        let html = r#"<html><body>
+            <p>每天签到获得 10 魔力</p>
+            <form method="post" action="attendance.php?action=makeup">
+                <p>您已经漏签 2 天</p>
+                <input type="submit" name="makeup" value="补签">
+            </form>
+        </body></html>"#;
+        let PageState::MakeUp(form) = site.page_state(html).unwrap() else {
+            panic!("应该可以补签");
+        };
+        assert_eq!(form.cost, None);
+
+        let html = "<html><body><p>您今天已经签到过了</p></body></html>";
+        assert!(matches!(site.page_state(html), Ok(PageState::Attended)));
+        let html = r#"<html><body><p>您今天已经签到过了</p>
+            <form method="post" action="attendance.php?action=makeup">
+                <p>您之前漏签了 2 天</p>
+                <input type="submit" name="makeup" value="补签">
+            </form>
+        </body></html>"#;
+        assert!(matches!(site.page_state(html), Ok(PageState::Attended)));
+        assert!(site.page_state("<html><body>维护中</body></html>").is_err());
+    }
+
+    #[test]
+    fn profile_test() {
+        let index = r#"<html><body><table id="info_block"><tr><td>
+            欢迎回来, <a href="userdetails.php?id=42"><b>alice</b></a> [退出]
+            魔力值 [使用]: 12,345.6 邀请 [发送]: 2
+            分享率: 2.345 上传量: 1.23 TB 下载量: 536.2 GB
+            <a href="messages.php">收件箱</a> 3 (1 新)
+        </td></tr></table></body></html>"#;
+        let (mut profile, link) = Profile::parse_info_block(index);
+        assert_eq!(link.as_deref(), Some("userdetails.php?id=42"));
+
+        let details = r#"<table>
+            <tr><td>等级</td><td><img alt="Power User" title="Power User"></td></tr>
+        </table>"#;
+        profile.merge_details(details);
+        assert_eq!(
+            profile,
+            Profile {
+                name: Some("alice".into()),
+                uploaded: Some("1.23 TB".into()),
+                downloaded: Some("536.2 GB".into()),
+                ratio: Some(2.345),
+                bonus: Some(12345.6),
+                class: Some("Power User".into()),
+                invites: Some(2),
+                unread: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn reward_test() {
+        let html = "<p>签到成功！这是您的第 <b>123</b> 次签到，已连续签到 <b>5</b> 天，\
+            今日签到排名：第 <b>3</b> 位，本次签到获得 <b>30</b> 个魔力值。</p>";
+        let reward = Reward::parse(html);
+        assert_eq!(
+            reward,
+            Reward {
+                bonus: Some(30.0),
+                streak: Some(5),
+                rank: Some(3),
+                total: Some(123),
+            }
+        );
+        assert_eq!(
+            reward.to_string(),
+            "获得 30 魔力值，连续签到 5 天，今日第 3 名，累计签到 123 天"
+        );
+        assert!(Reward::parse("<p>签到成功</p>").is_empty());
+    }
+}
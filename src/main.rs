@@ -1,8 +1,9 @@
 use anyhow::Result;
 use simple_logger::SimpleLogger;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     SimpleLogger::new()
         .with_colors(true)
         .with_level(log::LevelFilter::Debug)
@@ -15,8 +16,11 @@ async fn main() {
         .unwrap();
 
     match mma().await {
-        Ok(_) => {}
-        Err(e) => log::error!("Error: {}", e),
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("Error: {}", e);
+            ExitCode::FAILURE
+        }
     }
 }
 